@@ -0,0 +1,86 @@
+//! Fake SMTP server for trying out and testing email notifications locally.
+//! It accepts every message and prints it instead of delivering it.
+//!
+//! ```sh
+//! cargo run --example fake_smtp
+//! ```
+//!
+//! and point the service at it in `config.json`:
+//!
+//! ```json
+//! { "mail": { "kind": "smtp", "host": "127.0.0.1", "port": 2525, "from": "santa@localhost" } }
+//! ```
+
+use async_std::{
+    io::{prelude::BufReadExt, BufReader, WriteExt},
+    net::{TcpListener, TcpStream},
+    task,
+};
+use futures::StreamExt;
+
+#[async_std::main]
+async fn main() -> std::io::Result<()> {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:2525".to_string());
+    let listener = TcpListener::bind(&address).await?;
+    println!("Fake SMTP server listening on {address}");
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = stream?;
+        task::spawn(async move {
+            if let Err(err) = session(stream).await {
+                eprintln!("Session failed. {err}");
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn session(stream: TcpStream) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.clone());
+    let mut writer = stream;
+    writer.write_all(b"220 fake-smtp ready\r\n").await?;
+
+    let mut envelope = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let command = line.trim_end().to_ascii_uppercase();
+
+        if command.starts_with("EHLO") || command.starts_with("HELO") {
+            writer
+                .write_all(b"250-fake-smtp\r\n250 8BITMIME\r\n")
+                .await?;
+        } else if command.starts_with("MAIL FROM") || command.starts_with("RCPT TO") {
+            envelope.push(line.trim_end().to_string());
+            writer.write_all(b"250 OK\r\n").await?;
+        } else if command == "DATA" {
+            writer
+                .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
+                .await?;
+            let mut message = String::new();
+            loop {
+                let mut data = String::new();
+                if reader.read_line(&mut data).await? == 0 {
+                    return Ok(());
+                }
+                if data.trim_end_matches(['\r', '\n']) == "." {
+                    break;
+                }
+                message += data.strip_prefix('.').unwrap_or(&data);
+            }
+            println!("{}\n{}\n----", envelope.join("\n"), message.trim_end());
+            envelope.clear();
+            writer.write_all(b"250 OK: queued\r\n").await?;
+        } else if command == "QUIT" {
+            writer.write_all(b"221 Bye\r\n").await?;
+            return Ok(());
+        } else {
+            writer.write_all(b"250 OK\r\n").await?;
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Local webhook receiver for trying out and testing group webhooks without
+//! any external service.
+//!
+//! ```sh
+//! WEBHOOK_SECRET=<secret from /groups/webhooks/add> cargo run --example webhook_receiver
+//! ```
+//!
+//! Then register `http://127.0.0.1:8090/hook` for a group, after adding
+//! `"127.0.0.1"` to `webhooks.allowed_hosts` in the config. Every delivery is
+//! printed together with whether its signature is valid. Deliveries with a bad
+//! signature are answered with 401.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tide::{Request, Response, StatusCode};
+
+#[async_std::main]
+async fn main() -> tide::Result<()> {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:8090".to_string());
+    let secret = std::env::var("WEBHOOK_SECRET").unwrap_or_default();
+
+    let mut app = tide::with_state(secret);
+    app.at("/hook").post(receive);
+    println!("Waiting for webhooks on http://{address}/hook");
+    app.listen(address).await?;
+    Ok(())
+}
+
+async fn receive(mut req: Request<String>) -> tide::Result {
+    let body = req.body_string().await?;
+    let event = req
+        .header("X-Santa-Event")
+        .map_or("", |values| values.as_str())
+        .to_string();
+    let signature = req
+        .header("X-Santa-Signature")
+        .map_or("", |values| values.as_str())
+        .to_string();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(req.state().as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+    let valid = signature
+        .strip_prefix("sha256=")
+        .and_then(|hex_signature| hex::decode(hex_signature).ok())
+        .is_some_and(|bytes| mac.verify_slice(&bytes).is_ok());
+
+    println!(
+        "{event} ({}): {body}",
+        if valid {
+            "valid signature"
+        } else {
+            "BAD SIGNATURE"
+        }
+    );
+
+    Ok(Response::new(if valid {
+        StatusCode::Ok
+    } else {
+        StatusCode::Unauthorized
+    }))
+}
@@ -0,0 +1,286 @@
+//! Append-only log of administrative actions, one JSON object per line.
+//!
+//! Every attempt is recorded, including the ones that were refused, so
+//! admins can see who tried what. Entries are never rewritten and outlive the
+//! groups they mention; the log of a deleted group stays readable in the
+//! file. Entries carry the creation time of their group, so a new group
+//! under an old name doesn't inherit its log.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use tide::{Request, Response, StatusCode};
+
+use crate::render::reply;
+use crate::{read_body, returnable_value, views, Access, State};
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    DeleteGroup,
+    SetNewAdmin,
+    QuitAdmin,
+    CommitDraw,
+    SetSantas,
+    Kick,
+    Reopen,
+    Import,
+}
+
+impl Action {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Action::DeleteGroup => "delete_group",
+            Action::SetNewAdmin => "set_new_admin",
+            Action::QuitAdmin => "quit_admin",
+            Action::CommitDraw => "commit_draw",
+            Action::SetSantas => "set_santas",
+            Action::Kick => "kick",
+            Action::Reopen => "reopen",
+            Action::Import => "import",
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Entry {
+    pub timestamp: DateTime<Utc>,
+    /// Who acted, `None` for the scheduler.
+    pub actor: Option<String>,
+    pub action: Action,
+    pub group_name: String,
+    /// `Group::created_at` of the group, telling it apart from earlier groups
+    /// with the same name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_created_at: Option<DateTime<Utc>>,
+    /// The member the action was about, when it isn't the actor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// `"success"`, or why the action was refused or failed.
+    pub outcome: String,
+}
+
+#[derive(Clone)]
+pub struct AuditLog {
+    path: Arc<PathBuf>,
+    /// Keeps lines of concurrent writers from interleaving.
+    lock: Arc<Mutex<()>>,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> AuditLog {
+        AuditLog {
+            path: Arc::new(path),
+            lock: Arc::default(),
+        }
+    }
+
+    /// Appends an entry. A failed write is reported but doesn't fail the
+    /// action it describes.
+    pub fn record(
+        &self,
+        actor: Option<&str>,
+        action: Action,
+        group_name: &str,
+        group_created_at: Option<DateTime<Utc>>,
+        target: Option<&str>,
+        outcome: Result<(), &str>,
+    ) {
+        let entry = Entry {
+            timestamp: Utc::now(),
+            actor: actor.map(str::to_string),
+            action,
+            group_name: group_name.to_string(),
+            group_created_at,
+            target: target.map(str::to_string),
+            outcome: match outcome {
+                Ok(()) => "success".to_string(),
+                Err(reason) => reason.to_string(),
+            },
+        };
+        if let Err(err) = self.append(&entry) {
+            tracing::error!(error = %err, "Failed to write to audit log");
+        }
+    }
+
+    /// Records a change once `persist` has run: a success, or why it wasn't
+    /// saved, as the change is rolled back then.
+    pub fn record_saved(
+        &self,
+        actor: Option<&str>,
+        action: Action,
+        group_name: &str,
+        group_created_at: Option<DateTime<Utc>>,
+        target: Option<&str>,
+        saved: &io::Result<()>,
+    ) {
+        let reason = saved.as_ref().err().map(|err| format!("Not saved. {err}"));
+        self.record(
+            actor,
+            action,
+            group_name,
+            group_created_at,
+            target,
+            reason.as_deref().map_or(Ok(()), Err),
+        );
+    }
+
+    fn append(&self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let _guard = self.lock.lock().unwrap();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path.as_ref())?
+            .write_all(&line)
+    }
+
+    /// Entries about the group created at `group_created_at`, oldest first.
+    pub fn entries(
+        &self,
+        group_name: &str,
+        group_created_at: Option<DateTime<Utc>>,
+    ) -> io::Result<Vec<Entry>> {
+        let _guard = self.lock.lock().unwrap();
+        let file = match fs::File::open(self.path.as_ref()) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            // Skips a line cut short by a crash instead of hiding the whole log.
+            match serde_json::from_str::<Entry>(&line) {
+                Ok(entry)
+                    if entry.group_name == group_name
+                        && entry.group_created_at == group_created_at =>
+                {
+                    entries.push(entry)
+                }
+                Ok(_) => {}
+                Err(err) => tracing::warn!(error = %err, "Skipping malformed audit log line"),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[derive(serde::Deserialize, Default)]
+struct Data {
+    name: String,
+    group_name: String,
+}
+
+/// Reads the log of a group for one of its admins.
+async fn admin_entries(req: &mut Request<State>) -> Result<Vec<Entry>, (&'static str, u16)> {
+    let data: Data = read_body(req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return Err(("Bad data", 400));
+    }
+
+    let state = req.state();
+    let created_at = {
+        let guard = state.database.lock().unwrap();
+        let group = guard
+            .groups
+            .values()
+            .find(|g| g.name == data.group_name)
+            .ok_or(("Group with that name does not exist", 400))?;
+        if !group
+            .people
+            .iter()
+            .any(|p| p.name == data.name && matches!(p.access, Access::Admin))
+        {
+            return Err(("Only the administrator can read the audit log", 403));
+        }
+        group.created_at
+    };
+
+    state
+        .audit
+        .entries(&data.group_name, created_at)
+        .map_err(|err| {
+            tracing::error!(error = %err, "Failed to read audit log");
+            ("Failed to read the audit log", 500)
+        })
+}
+
+pub async fn list(mut req: Request<State>) -> tide::Result {
+    match admin_entries(&mut req).await {
+        Ok(entries) => reply(&req, 200, &views::AuditEntries { entries: &entries }),
+        Err((text, code)) => returnable_value(&req, text, code),
+    }
+}
+
+/// The same entries as `list`, as JSON Lines.
+pub async fn export(mut req: Request<State>) -> tide::Result {
+    let entries = match admin_entries(&mut req).await {
+        Ok(entries) => entries,
+        Err((text, code)) => return returnable_value(&req, text, code),
+    };
+
+    let mut body = String::new();
+    for entry in &entries {
+        body += &serde_json::to_string(entry)?;
+        body.push('\n');
+    }
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(body);
+    res.set_content_type("application/jsonl; charset=utf-8");
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::testing::TestApp;
+
+    #[async_std::test]
+    async fn a_new_group_under_an_old_name_has_its_own_log() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        let body = json!({ "name": "alice", "group_name": "party", "member": "bob" });
+        assert_eq!(app.post("/groups/kick", body).await.0, 200);
+        let body = json!({ "name": "alice", "group_name": "party" });
+        assert_eq!(app.post("/groups/delete", body.clone()).await.0, 200);
+
+        app.group("party", &["alice"]).await;
+        let (code, message) = app.post("/groups/audit", body).await;
+        assert_eq!(code, 200, "{message}");
+        assert_eq!(message["entries"], json!([]));
+    }
+
+    #[async_std::test]
+    async fn unsaved_changes_are_not_logged_as_done() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob", "carol"]).await;
+        app.state.journal.fail_writes();
+
+        let body = json!({ "name": "alice", "group_name": "party", "member": "bob" });
+        assert_eq!(app.post("/groups/kick", body).await.0, 500);
+        let body = json!({ "name": "alice", "group_name": "party", "name_new_admin": "carol" });
+        assert_eq!(app.post("/groups/new_admin", body).await.0, 500);
+        let body = json!({ "name": "alice", "group_name": "party" });
+        assert_eq!(app.post("/groups/set_santas", body.clone()).await.0, 500);
+        assert_eq!(app.post("/groups/delete", body).await.0, 500);
+
+        let created_at = app.state.database.lock().unwrap().groups[&0].created_at;
+        let entries = app.state.audit.entries("party", created_at).unwrap();
+        assert_eq!(entries.len(), 4);
+        for entry in entries {
+            assert!(entry.outcome.starts_with("Not saved."), "{entry:?}");
+        }
+    }
+}
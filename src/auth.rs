@@ -0,0 +1,139 @@
+//! Member tokens.
+//!
+//! Requests name the member they come from, and names are easy to guess.
+//! What only that member may see, whom they gift, their giftee's address and
+//! the details other members keep private, also takes the secret token they
+//! got when they created or joined the group. Only its SHA-256 is stored.
+//!
+//! Members added by an import, and members from before tokens, get theirs
+//! by email: an import sends one to every new member with an email, and
+//! `/groups/token` sends a new one to the member's email, which is also how
+//! a lost token is replaced.
+
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tide::Request;
+
+use crate::journal::Event;
+use crate::{not_saved, persist, read_body, returnable_value, Person, State};
+
+fn digest(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+impl Person {
+    /// Gives the member a new token, which replaces any earlier one.
+    pub fn issue_token(&mut self) -> String {
+        let mut bytes = [0u8; 24];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        self.token_hash = Some(digest(&token));
+        token
+    }
+
+    /// Whether `token` is the member's. Members without a token have to get
+    /// one first, see the module docs.
+    pub fn has_token(&self, token: &str) -> bool {
+        !token.is_empty() && self.token_hash.as_deref() == Some(digest(token).as_str())
+    }
+}
+
+/// Sends the caller a new token by email. Anyone can ask for someone else,
+/// which only costs the member their old token, the new one goes to them.
+pub async fn resend(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
+    struct Data {
+        name: String,
+        group_name: String,
+    }
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+
+    let state = req.state();
+    let mut guard = state.database.lock().unwrap();
+
+    let Some(group) = guard
+        .groups
+        .values_mut()
+        .find(|g| g.name == data.group_name)
+    else {
+        return returnable_value(&req, "Group with that name does not exist", 400);
+    };
+    let Some(person) = group.people.iter_mut().find(|p| p.name == data.name) else {
+        return returnable_value(&req, "You are not a member of this group", 403);
+    };
+    if person.email.is_none() {
+        return returnable_value(&req, "There is no email to send your token to", 400);
+    }
+    let token = person.issue_token();
+
+    if persist(state, &mut guard, &data.group_name, Event::TokenIssued).is_err() {
+        return not_saved(&req);
+    }
+    if let Some(person) = guard
+        .groups
+        .values()
+        .find(|g| g.name == data.group_name)
+        .and_then(|g| g.people.iter().find(|p| p.name == data.name))
+    {
+        state.mailer.token(&data.group_name, person, &token);
+    }
+
+    returnable_value(&req, "A new token is sent to your email", 200)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::testing::TestApp;
+
+    #[async_std::test]
+    async fn only_the_members_token_shows_their_assignment() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob", "carol"]).await;
+        app.draw("party", "alice").await;
+
+        for token in ["", "guess", &app.token("alice")] {
+            let body = json!({ "name": "bob", "group_name": "party", "token": token });
+            let (code, message) = app.post("/to-who-gift", body.clone()).await;
+            assert_eq!(code, 403, "{message}");
+
+            let (code, message) = app.post("/groups/members", body.clone()).await;
+            assert_eq!(code, 200);
+            assert!(message["people"].is_null(), "{message}");
+            assert_eq!(message["persons"], 3);
+
+            let mut body = body;
+            body["display_name"] = json!("Mallory");
+            assert_eq!(app.post("/groups/profile", body).await.0, 403);
+        }
+
+        let body = json!({ "name": "bob", "group_name": "party" });
+        let (code, message) = app.post("/to-who-gift", body.clone()).await;
+        assert_eq!(code, 200, "{message}");
+        let (_, message) = app.post("/groups/members", body).await;
+        assert_eq!(message["people"].as_array().unwrap().len(), 3);
+    }
+
+    #[async_std::test]
+    async fn a_new_token_replaces_the_old_one() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        let body = json!({ "name": "bob", "group_name": "party" });
+        let (code, message) = app.post("/groups/token", body.clone()).await;
+        assert_eq!(code, 400, "{message}");
+
+        let mut profile = body.clone();
+        profile["email"] = json!("bob@example.com");
+        assert_eq!(app.post("/groups/profile", profile).await.0, 200);
+        assert_eq!(app.post("/groups/token", body.clone()).await.0, 200);
+
+        let mut profile = body;
+        profile["display_name"] = json!("Bob");
+        assert_eq!(app.post("/groups/profile", profile).await.0, 403);
+    }
+}
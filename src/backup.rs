@@ -0,0 +1,388 @@
+//! Backups of the whole database, taken on a schedule and on demand by the
+//! server operator, and restoring from one.
+//!
+//! A backup is a snapshot in the `data.base` format. Scheduled backups go to
+//! `backup-<time>.json` in the configured directory, and only the newest
+//! `keep` of them are kept. A restore first saves the state it replaces as
+//! `pre-restore-<time>.json` next to them.
+
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use async_std::task;
+use chrono::Utc;
+use tide::{Request, Response, StatusCode};
+
+use crate::render::{reply_with_status, Message};
+use crate::{is_valid_name, storage, vault, DataBase, State};
+
+const SCHEDULED_PREFIX: &str = "backup-";
+const PRE_RESTORE_PREFIX: &str = "pre-restore-";
+
+/// Takes a scheduled backup every `every_hours`.
+pub fn start(state: State) {
+    let every_hours = state.backup.every_hours;
+    if every_hours == 0 {
+        return;
+    }
+    task::spawn(async move {
+        loop {
+            task::sleep(Duration::from_secs(every_hours * 60 * 60)).await;
+            let result = snapshot(&state)
+                .and_then(|data| write(&state.backup.directory, SCHEDULED_PREFIX, &data))
+                .and_then(|path| {
+                    rotate(&state.backup.directory, state.backup.keep)?;
+                    Ok(path)
+                });
+            match result {
+                Ok(path) => tracing::info!(path = %path.display(), "Backup written"),
+                Err(err) => tracing::error!(error = %err, "Scheduled backup failed"),
+            }
+        }
+    });
+}
+
+/// Serializes the database while holding the lock, so the snapshot never
+/// has half of a change.
+fn snapshot(state: &State) -> io::Result<Vec<u8>> {
+    Ok(storage::encode(&state.database.lock().unwrap())?)
+}
+
+fn write(directory: &Path, prefix: &str, data: &[u8]) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(directory)?;
+    let path = directory.join(format!(
+        "{prefix}{}.json",
+        Utc::now().format("%Y%m%dT%H%M%SZ")
+    ));
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::write(&temp_path, data)
+        .and_then(|_| std::fs::rename(&temp_path, &path))
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Failed to write backup {}. {err}", path.display()),
+            )
+        })?;
+    Ok(path)
+}
+
+/// Deletes all but the newest `keep` scheduled backups. The timestamp in
+/// the name sorts them.
+fn rotate(directory: &Path, keep: usize) -> io::Result<()> {
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(SCHEDULED_PREFIX) && name.ends_with(".json") {
+            backups.push(name);
+        }
+    }
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep);
+    for name in &backups[..excess] {
+        std::fs::remove_file(directory.join(name))?;
+    }
+    Ok(())
+}
+
+/// Checks a database before it replaces the live one: group names are
+/// unique, every person is in one group only, open groups have no
+/// assignments, and in closed groups every member gives to another member
+/// and receives from exactly one.
+fn validate(database: &DataBase, vault: &vault::Vault) -> Result<(), String> {
+    let mut group_names = HashSet::new();
+    let mut people = HashSet::new();
+    for group in database.groups.values() {
+        if group.name.is_empty() {
+            return Err("a group has no name".to_string());
+        }
+        if !group_names.insert(group.name.as_str()) {
+            return Err(format!("group name \"{}\" is used twice", group.name));
+        }
+
+        let mut members = HashSet::new();
+        for person in &group.people {
+            if !is_valid_name(&person.name) || !members.insert(person.name.as_str()) {
+                return Err(format!(
+                    "group \"{}\" has an invalid or repeated member name",
+                    group.name
+                ));
+            }
+            if !people.insert(person.name.as_str()) {
+                return Err(format!("\"{}\" is in more than one group", person.name));
+            }
+        }
+
+        let mut giftees = HashSet::new();
+        for person in &group.people {
+            if !group.closed {
+                if !person.santa_to.is_empty() {
+                    return Err(format!(
+                        "\"{}\" in open group \"{}\" has an assignment",
+                        person.name, group.name
+                    ));
+                }
+                continue;
+            }
+
+            // Plain text assignments come from older versions and are sealed
+            // after the restore.
+            let giftee = if vault::is_sealed(&person.santa_to) {
+                vault
+                    .open(&group.name, &person.name, &person.santa_to)
+                    .ok_or_else(|| {
+                        format!(
+                            "the assignment of \"{}\" in group \"{}\" can't be opened with this server's key",
+                            person.name, group.name
+                        )
+                    })?
+            } else {
+                person.santa_to.clone()
+            };
+            if giftee == person.name || !members.contains(giftee.as_str()) {
+                return Err(format!(
+                    "\"{}\" in group \"{}\" gives to someone who isn't another member",
+                    person.name, group.name
+                ));
+            }
+            if !giftees.insert(giftee) {
+                return Err(format!(
+                    "someone in group \"{}\" gets two gifts",
+                    group.name
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Answers with the error to return when the request doesn't carry the
+/// configured token.
+fn authorize(req: &Request<State>) -> Result<(), (&'static str, StatusCode)> {
+    let Some(token) = &req.state().backup.token else {
+        return Err(("Operator endpoints are disabled", StatusCode::Forbidden));
+    };
+    let given = req
+        .header("Authorization")
+        .and_then(|value| value.last().as_str().strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if constant_time_eq(given.as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(("Bad operator token", StatusCode::Unauthorized))
+    }
+}
+
+/// Operator endpoints answer with the real HTTP status, unlike the API,
+/// so that scripts like `curl -f -o backup.json` notice failures.
+fn answer(req: &Request<State>, text: &str, status: StatusCode) -> tide::Result {
+    reply_with_status(req, status, &Message(text))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub async fn download(req: Request<State>) -> tide::Result {
+    if let Err((text, status)) = authorize(&req) {
+        return answer(&req, text, status);
+    }
+
+    let data = snapshot(req.state())?;
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(data);
+    res.set_content_type("application/json");
+    res.insert_header(
+        "Content-Disposition",
+        format!(
+            "attachment; filename=\"{SCHEDULED_PREFIX}{}.json\"",
+            Utc::now().format("%Y%m%dT%H%M%SZ")
+        ),
+    );
+    Ok(res)
+}
+
+pub async fn restore(mut req: Request<State>) -> tide::Result {
+    if let Err((text, status)) = authorize(&req) {
+        return answer(&req, text, status);
+    }
+
+    let body = req.body_bytes().await.unwrap_or_default();
+    let state = req.state();
+    let mut restored = match serde_json::from_slice(&body)
+        .map_err(|err| err.to_string())
+        .and_then(storage::decode)
+        .and_then(|database| validate(&database, &state.vault).map(|_| database))
+    {
+        Ok(database) => database,
+        Err(err) => {
+            tracing::warn!(error = %err, "Rejected backup for restore");
+            return answer(&req, &format!("Bad backup: {err}"), StatusCode::BadRequest);
+        }
+    };
+    state.vault.seal_plaintext(&mut restored);
+
+    let mut guard = state.database.lock().unwrap();
+    let previous = storage::encode(&guard)?;
+    if let Err(err) = write(&state.backup.directory, PRE_RESTORE_PREFIX, &previous) {
+        tracing::error!(error = %err, "Failed to back up the database before restoring");
+        return answer(
+            &req,
+            "Failed to back up the current database",
+            StatusCode::InternalServerError,
+        );
+    }
+    if let Err(err) = state.journal.replace(&restored) {
+        tracing::error!(error = %err, "Failed to write the restored database");
+        // The journal may be empty already, the snapshot has to hold what
+        // is still in memory then.
+        if let Err(err) = state.journal.compact(&guard) {
+            tracing::error!(error = %err, "Failed to save the database after a failed restore");
+        }
+        return answer(
+            &req,
+            "Failed to write the restored database",
+            StatusCode::InternalServerError,
+        );
+    }
+    *guard = restored;
+
+    tracing::warn!(groups = guard.groups.len(), "Database restored from backup");
+    answer(
+        &req,
+        &format!("Database restored. Groups: {}", guard.groups.len()),
+        StatusCode::Ok,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::{json, Value};
+    use tide::http::{Method, Request};
+    use tide::StatusCode;
+
+    use crate::testing::{url, TestApp, OPERATOR_TOKEN};
+    use crate::{journal, storage};
+
+    async fn download(app: &TestApp) -> Value {
+        let mut req = Request::new(Method::Get, url("/admin/backup"));
+        req.insert_header("Authorization", format!("Bearer {OPERATOR_TOKEN}"));
+        app.respond(req).await.body_json().await.unwrap()
+    }
+
+    async fn restore(app: &TestApp, backup: &Value) -> (u64, Value) {
+        let mut req = Request::new(Method::Post, url("/admin/restore"));
+        req.insert_header("Authorization", format!("Bearer {OPERATOR_TOKEN}"));
+        req.insert_header("Accept", "application/json");
+        req.set_body(backup.clone());
+        let mut res = app.respond(req).await;
+        let answer: Value = res.body_json().await.unwrap();
+        let code = answer["code"].as_u64().unwrap();
+        assert_eq!(u16::from(res.status()) as u64, code);
+        (code, answer["message"].clone())
+    }
+
+    fn group_names(app: &TestApp) -> Vec<String> {
+        let guard = app.state.database.lock().unwrap();
+        let mut names: Vec<_> = guard.groups.values().map(|g| g.name.clone()).collect();
+        names.sort();
+        names
+    }
+
+    #[async_std::test]
+    async fn operator_errors_are_http_errors() {
+        let app = TestApp::new();
+        for path in ["/admin/backup", "/admin/restore"] {
+            let method = if path == "/admin/backup" {
+                Method::Get
+            } else {
+                Method::Post
+            };
+            let res = app.respond(Request::new(method, url(path))).await;
+            assert_eq!(res.status(), StatusCode::Unauthorized, "{path}");
+
+            let mut req = Request::new(method, url(path));
+            req.insert_header("Authorization", "Bearer wrong");
+            let res = app.respond(req).await;
+            assert_eq!(res.status(), StatusCode::Unauthorized, "{path}");
+        }
+
+        let app = TestApp::with_config(|config| config.backup.token = None);
+        let mut req = Request::new(Method::Get, url("/admin/backup"));
+        req.insert_header("Authorization", format!("Bearer {OPERATOR_TOKEN}"));
+        assert_eq!(app.respond(req).await.status(), StatusCode::Forbidden);
+    }
+
+    #[async_std::test]
+    async fn bad_backups_are_http_errors() {
+        let app = TestApp::new();
+        let (code, _) = restore(&app, &json!({ "version": 1 })).await;
+        assert_eq!(code, 400);
+    }
+
+    #[async_std::test]
+    async fn restore_leaves_no_journal_to_replay() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        let backup = download(&app).await;
+        app.group("other", &["carol"]).await;
+
+        let (code, message) = restore(&app, &backup).await;
+        assert_eq!(code, 200, "{message}");
+        assert_eq!(group_names(&app), ["party"]);
+
+        assert!(fs::read_to_string(app.dir().join("data.journal"))
+            .unwrap()
+            .is_empty());
+        let mut database = storage::load(&app.dir().join("data.base")).unwrap();
+        journal::replay(&app.dir().join("data.journal"), &mut database).unwrap();
+        assert_eq!(database.groups.len(), 1);
+    }
+
+    #[async_std::test]
+    async fn restore_refuses_a_person_in_two_groups() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        app.group("other", &["carol"]).await;
+        let mut backup = download(&app).await;
+        for group in backup["data"]["groups"]
+            .as_object_mut()
+            .unwrap()
+            .values_mut()
+        {
+            if group["name"] == "other" {
+                let mut bob = group["people"][0].clone();
+                bob["name"] = json!("bob");
+                bob["access"] = json!("user");
+                group["people"].as_array_mut().unwrap().push(bob);
+            }
+        }
+
+        let (code, message) = restore(&app, &backup).await;
+        assert_eq!(code, 400);
+        assert!(
+            message.as_str().unwrap().contains("more than one group"),
+            "{message}"
+        );
+    }
+
+    #[async_std::test]
+    async fn failed_restore_keeps_the_database() {
+        let app = TestApp::new();
+        app.group("party", &["alice"]).await;
+        let backup = download(&app).await;
+        let body = json!({ "name": "alice", "group_name": "party" });
+        assert_eq!(app.post("/groups/delete", body).await.0, 200);
+        app.group("other", &["carol"]).await;
+
+        app.state.journal.fail_writes();
+        let (code, _) = restore(&app, &backup).await;
+        assert_eq!(code, 500);
+        assert_eq!(group_names(&app), ["other"]);
+    }
+}
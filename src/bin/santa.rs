@@ -0,0 +1,323 @@
+//! Command-line client for the secret santa service.
+//!
+//! The service identifies callers by name and a token, so `santa login <name>`
+//! stores the name together with the server address and every other command
+//! sends it. The token comes with `create-group` or `join-group`, which save
+//! it too, or by email with `request-token`.
+
+use std::{fs, path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
+
+#[derive(Parser)]
+#[command(name = "santa", about = "Secret santa service client")]
+struct Cli {
+    /// Server address, overrides the one saved by `login`
+    #[arg(long, env = "SANTA_SERVER")]
+    server: Option<String>,
+    /// Print the raw JSON replies instead of tables
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Remember who you are and which server to talk to
+    Login {
+        name: String,
+        /// Your token, if you already have one
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Forget the saved credentials
+    Logout,
+    /// List all groups
+    GetGroups,
+    /// Create a group and become its admin
+    CreateGroup { group_name: String },
+    /// Join an open group
+    JoinGroup { group_name: String },
+    /// Show the members of a group
+    GetMembers { group_name: String },
+    /// Give admin rights to another member
+    SetNewAdmin {
+        group_name: String,
+        name_new_admin: String,
+    },
+    /// Give up your admin rights
+    QuitAdmin { group_name: String },
+    /// Leave a group
+    QuitGroup { group_name: String },
+    /// Delete a group
+    DeleteGroup { group_name: String },
+    /// Publish the draw commitment, then run the draw and close the group
+    /// when called again
+    SetSantas { group_name: String },
+    /// Show who you are secret santa to
+    GetGifted { group_name: String },
+    /// Get a new token by email
+    RequestToken { group_name: String },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct Credentials {
+    name: String,
+    server: String,
+    #[serde(default)]
+    token: String,
+}
+
+const DEFAULT_SERVER: &str = "http://127.0.0.1:8080";
+
+fn credentials_path() -> PathBuf {
+    if let Some(path) = std::env::var_os("SANTA_CREDENTIALS") {
+        return PathBuf::from(path);
+    }
+    let home = std::env::var_os("HOME").unwrap_or_else(|| ".".into());
+    PathBuf::from(home)
+        .join(".config")
+        .join("santa")
+        .join("credentials.json")
+}
+
+fn load_credentials() -> Option<Credentials> {
+    let file = fs::File::open(credentials_path()).ok()?;
+    serde_json::from_reader(file).ok()
+}
+
+fn save_credentials(credentials: &Credentials) -> std::io::Result<()> {
+    let path = credentials_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(credentials)?)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("santa: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<bool, String> {
+    let saved = load_credentials();
+    let server = cli
+        .server
+        .clone()
+        .or_else(|| saved.as_ref().map(|c| c.server.clone()))
+        .unwrap_or_else(|| DEFAULT_SERVER.to_string());
+
+    // The first `set-santas` only publishes the commitment.
+    let draw_again = match &cli.command {
+        Command::SetSantas { group_name } => Some(group_name.clone()),
+        _ => None,
+    };
+
+    let (path, body) = match cli.command {
+        Command::Login { name, token } => {
+            let token = token.unwrap_or_default();
+            save_credentials(&Credentials {
+                name,
+                server,
+                token,
+            })
+            .map_err(|err| format!("Failed to save credentials. {err}"))?;
+            println!("Credentials saved to {}", credentials_path().display());
+            return Ok(true);
+        }
+        Command::Logout => {
+            match fs::remove_file(credentials_path()) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(format!("Failed to remove credentials. {err}")),
+            }
+            return Ok(true);
+        }
+        Command::GetGroups => ("/groups/list", None),
+        Command::CreateGroup { group_name } => {
+            ("/groups/create", Some(json!({ "group_name": group_name })))
+        }
+        Command::JoinGroup { group_name } => {
+            ("/groups/join", Some(json!({ "group_name": group_name })))
+        }
+        Command::GetMembers { group_name } => {
+            ("/groups/members", Some(json!({ "group_name": group_name })))
+        }
+        Command::SetNewAdmin {
+            group_name,
+            name_new_admin,
+        } => (
+            "/groups/new_admin",
+            Some(json!({ "group_name": group_name, "name_new_admin": name_new_admin })),
+        ),
+        Command::QuitAdmin { group_name } => (
+            "/groups/quit_admin",
+            Some(json!({ "group_name": group_name })),
+        ),
+        Command::QuitGroup { group_name } => {
+            ("/groups/quit", Some(json!({ "group_name": group_name })))
+        }
+        Command::DeleteGroup { group_name } => {
+            ("/groups/delete", Some(json!({ "group_name": group_name })))
+        }
+        Command::SetSantas { group_name } => (
+            "/groups/set_santas",
+            Some(json!({ "group_name": group_name })),
+        ),
+        Command::GetGifted { group_name } => {
+            ("/to-who-gift", Some(json!({ "group_name": group_name })))
+        }
+        Command::RequestToken { group_name } => {
+            ("/groups/token", Some(json!({ "group_name": group_name })))
+        }
+    };
+
+    let reply = match body {
+        None => request(ureq::get(&format!("{server}{path}")), None)?,
+        Some(mut body) => {
+            let saved = saved
+                .as_ref()
+                .ok_or("Not logged in. Run `santa login <name>` first")?;
+            body["name"] = Value::from(saved.name.as_str());
+            body["token"] = Value::from(saved.token.as_str());
+            request(ureq::post(&format!("{server}{path}")), Some(body))?
+        }
+    };
+
+    let ok = reply["code"].as_u64() == Some(200);
+    // Creating or joining a group hands out a new token.
+    if let (true, Some(token), Some(saved)) = (ok, reply["message"]["token"].as_str(), saved) {
+        save_credentials(&Credentials {
+            token: token.to_string(),
+            ..saved
+        })
+        .map_err(|err| format!("Failed to save your token. {err}"))?;
+    }
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&reply).unwrap());
+    } else {
+        print_message(&reply["message"], ok);
+        let committed = reply["message"]
+            .as_str()
+            .is_some_and(|text| text.starts_with("Draw commitment published"));
+        if let (true, Some(group_name)) = (ok && committed, draw_again) {
+            println!(
+                "Run `santa set-santas {group_name}` again to run the draw and close the group"
+            );
+        }
+    }
+    Ok(ok)
+}
+
+fn request(req: ureq::Request, body: Option<Value>) -> Result<Value, String> {
+    let req = req.set("Accept", "application/json");
+    let res = match body {
+        Some(body) => req.send_json(body),
+        None => req.call(),
+    };
+    let res = match res {
+        Ok(res) => res,
+        // The service answers errors with the same JSON envelope.
+        Err(ureq::Error::Status(_, res)) => res,
+        Err(err) => return Err(format!("Request failed. {err}")),
+    };
+    res.into_json()
+        .map_err(|err| format!("Unexpected reply from server. {err}"))
+}
+
+fn print_message(message: &Value, ok: bool) {
+    if let Some(text) = message.as_str() {
+        if ok {
+            println!("{text}");
+        } else {
+            eprintln!("{text}");
+        }
+    } else if let Some(token) = message["token"].as_str() {
+        println!("{}", field(message, "text"));
+        println!(
+            "Your token {token} is saved to {}",
+            credentials_path().display()
+        );
+    } else if let Some(gifted) = message["gifted"].as_str() {
+        println!("You secret santa to - {gifted}");
+        if let Some(address) = message["address"].as_str() {
+            println!("Shipping address:\n{address}");
+        }
+    } else if message["persons"].is_number() {
+        let row = vec![
+            field(message, "group_name"),
+            field(message, "persons"),
+            field(message, "closed"),
+        ];
+        print_table(&["GROUP NAME", "PERSONS", "CLOSED"], vec![row]);
+    } else if let Some(people) = message["people"].as_array() {
+        let rows = people
+            .iter()
+            .enumerate()
+            .map(|(id, person)| {
+                vec![
+                    id.to_string(),
+                    field(person, "name"),
+                    field(person, "access"),
+                ]
+            })
+            .collect();
+        print_table(&["ID", "NAME", "ACCESS"], rows);
+    } else if let Some(groups) = message["groups"].as_array() {
+        let rows = groups
+            .iter()
+            .map(|group| {
+                vec![
+                    field(group, "id"),
+                    field(group, "name"),
+                    field(group, "persons"),
+                    field(group, "closed"),
+                ]
+            })
+            .collect();
+        print_table(&["ID", "GROUP NAME", "PERSONS", "CLOSED"], rows);
+    } else {
+        println!("{message}");
+    }
+}
+
+fn field(value: &Value, key: &str) -> String {
+    match &value[key] {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+
+    line(headers.to_vec());
+    for row in &rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
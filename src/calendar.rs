@@ -0,0 +1,253 @@
+//! iCalendar export of group dates, so members can subscribe to the draw
+//! deadline and the gift exchange from their calendar apps.
+//!
+//! Calendar apps fetch the feed by URL, so the `name` of the personal feed
+//! is all there is to go by and anyone can type someone else's. The
+//! personal feed therefore never names a group, and looks the same for a
+//! name that is in no group at all.
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use tide::{Request, Response, StatusCode};
+
+use crate::{returnable_value, Group, State};
+
+pub async fn group(req: Request<State>) -> tide::Result {
+    #[derive(serde::Deserialize, Default)]
+    struct Query {
+        group_name: String,
+    }
+    let Query { group_name } = req.query().unwrap_or_default();
+
+    let guard = req.state().database.lock().unwrap();
+    match guard.groups.iter().find(|(_, g)| g.name == group_name) {
+        None => returnable_value(&req, "There is no group with that name", 400),
+        Some((id, group)) => Ok(calendar(
+            &format!("Secret Santa: {}", group.name),
+            &events(&id.to_string(), &format!(": {}", group.name), group),
+        )),
+    }
+}
+
+pub async fn user(req: Request<State>) -> tide::Result {
+    #[derive(serde::Deserialize, Default)]
+    struct Query {
+        name: String,
+    }
+    let Query { name } = req.query().unwrap_or_default();
+
+    if name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+
+    let guard = req.state().database.lock().unwrap();
+    let mut vevents = Vec::new();
+    for (id, group) in &guard.groups {
+        if group.people.iter().any(|p| p.name == name) {
+            // Unlike the group id, this doesn't tie the events to the feed
+            // of the group.
+            let uid = hex::encode(&Sha256::digest(format!("{name}\0{id}"))[..8]);
+            vevents.extend(events(&uid, "", group));
+        }
+    }
+
+    Ok(calendar(&format!("Secret Santa: {name}"), &vevents))
+}
+
+/// Events for the dates of a group. `title` is appended to their summaries.
+fn events(uid: &str, title: &str, group: &Group) -> Vec<String> {
+    let mut vevents = Vec::new();
+    let draw = match (group.draw_date, group.auto_draw_at) {
+        (Some(date), _) => Some((date, group.draw_all_day)),
+        (None, date) => date.map(|date| (date, false)),
+    };
+    if let Some((date, all_day)) = draw {
+        vevents.push(vevent(
+            &format!("draw-{uid}"),
+            date,
+            all_day,
+            &format!("Secret Santa draw{title}"),
+            "Members of the group learn whom they gift.",
+        ));
+    }
+    if let Some(date) = group.exchange_date {
+        vevents.push(vevent(
+            &format!("exchange-{uid}"),
+            date,
+            group.exchange_all_day,
+            &format!("Secret Santa gift exchange{title}"),
+            "Bring your gift!",
+        ));
+    }
+    vevents
+}
+
+/// An hour long event at `start`, or with `all_day`, the whole day of
+/// `start` wherever the calendar is, which a plain date means.
+fn vevent(
+    uid: &str,
+    start: DateTime<Utc>,
+    all_day: bool,
+    summary: &str,
+    description: &str,
+) -> String {
+    let (start, duration) = if all_day {
+        (
+            format!("DTSTART;VALUE=DATE:{}", start.format("%Y%m%d")),
+            "DURATION:P1D",
+        )
+    } else {
+        (format!("DTSTART:{}", format_time(start)), "DURATION:PT1H")
+    };
+    [
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:{uid}@secret-santa-service"),
+        format!("DTSTAMP:{}", format_time(Utc::now())),
+        start,
+        duration.to_string(),
+        format!("SUMMARY:{}", escape_text(summary)),
+        format!("DESCRIPTION:{}", escape_text(description)),
+        "END:VEVENT".to_string(),
+    ]
+    .map(|line| fold(&line))
+    .concat()
+}
+
+fn calendar(name: &str, vevents: &[String]) -> Response {
+    let mut body = [
+        "BEGIN:VCALENDAR",
+        "VERSION:2.0",
+        "PRODID:-//secret-santa-service//EN",
+        "CALSCALE:GREGORIAN",
+        "METHOD:PUBLISH",
+    ]
+    .map(fold)
+    .concat();
+    body += &fold(&format!("X-WR-CALNAME:{}", escape_text(name)));
+    body += &vevents.concat();
+    body += &fold("END:VCALENDAR");
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(body);
+    res.set_content_type("text/calendar; charset=utf-8");
+    res
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escapes a TEXT value as described in RFC 5545, section 3.3.11.
+fn escape_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out += "\\\\",
+            ';' => out += "\\;",
+            ',' => out += "\\,",
+            '\n' => out += "\\n",
+            '\r' => {}
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Ends a content line with CRLF, folding it so no line is longer than
+/// 75 octets. Continuation lines start with a space.
+fn fold(line: &str) -> String {
+    let mut out = String::with_capacity(line.len() + 8);
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out += "\r\n ";
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out += "\r\n";
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::testing::TestApp;
+
+    #[async_std::test]
+    async fn personal_calendar_names_no_group() {
+        let app = TestApp::new();
+        app.group("secret-party", &["alice", "bob"]).await;
+        let body = json!({
+            "name": "alice",
+            "group_name": "secret-party",
+            "exchange_date": "2030-12-24",
+        });
+        assert_eq!(app.post("/groups/set_dates", body).await.0, 200);
+
+        let mut res = app.get("/users/calendar.ics?name=bob").await;
+        assert_eq!(u16::from(res.status()), 200);
+        let calendar = res.body_string().await.unwrap();
+        assert!(
+            calendar.contains("DTSTART;VALUE=DATE:20301224\r\nDURATION:P1D\r\n"),
+            "{calendar}"
+        );
+        assert!(!calendar.contains("secret-party"), "{calendar}");
+
+        // A name in no group gets an empty calendar, not an error.
+        let mut res = app.get("/users/calendar.ics?name=eve").await;
+        assert_eq!(u16::from(res.status()), 200);
+        assert!(!res.body_string().await.unwrap().contains("VEVENT"));
+    }
+
+    #[async_std::test]
+    async fn only_plain_dates_are_whole_days() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        let set = |dates: serde_json::Value| {
+            let mut body = json!({ "name": "alice", "group_name": "party" });
+            body.as_object_mut()
+                .unwrap()
+                .extend(dates.as_object().unwrap().clone());
+            app.post("/groups/set_dates", body)
+        };
+        let calendar = || async {
+            let mut res = app.get("/groups/calendar.ics?group_name=party").await;
+            assert_eq!(u16::from(res.status()), 200);
+            res.body_string().await.unwrap()
+        };
+
+        let dates = json!({ "draw_date": "2030-12-01T18:00:00Z", "exchange_date": "2030-12-24" });
+        assert_eq!(set(dates).await.0, 200);
+        let answer = calendar().await;
+        assert!(
+            answer.contains("DTSTART:20301201T180000Z\r\nDURATION:PT1H\r\n"),
+            "{answer}"
+        );
+        assert!(
+            answer.contains("DTSTART;VALUE=DATE:20301224\r\n"),
+            "{answer}"
+        );
+
+        // Setting one date leaves the other as it was.
+        assert_eq!(set(json!({ "draw_date": "2030-12-01" })).await.0, 200);
+        let answer = calendar().await;
+        assert!(
+            answer.contains("DTSTART;VALUE=DATE:20301201\r\n"),
+            "{answer}"
+        );
+        assert!(
+            answer.contains("DTSTART;VALUE=DATE:20301224\r\n"),
+            "{answer}"
+        );
+
+        let dates = json!({ "draw_date": "none", "exchange_date": "2030-12-24T17:00:00+01:00" });
+        assert_eq!(set(dates).await.0, 200);
+        let answer = calendar().await;
+        assert!(!answer.contains("draw-"), "{answer}");
+        assert!(answer.contains("DTSTART:20301224T160000Z\r\n"), "{answer}");
+        assert!(!answer.contains("VALUE=DATE"), "{answer}");
+    }
+}
@@ -0,0 +1,269 @@
+//! Service configuration, read from `config.json` (or the file named by
+//! `SANTA_CONFIG`). Every field is optional and a missing file means defaults.
+
+use std::{fs::File, path::PathBuf};
+
+#[derive(serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Address and port to listen on.
+    pub listen: String,
+    /// Serve HTTPS instead of plain HTTP.
+    pub tls: Option<TlsConfig>,
+    /// Address the service is reachable at, used for links in emails.
+    pub public_url: String,
+    /// Snapshot of the database, see `storage`.
+    pub database: PathBuf,
+    /// Changes since the last snapshot, see `journal`.
+    pub journal: PathBuf,
+    pub mail: MailConfig,
+    /// Key file for encrypting assignments, created on first start. Keep it
+    /// out of reach of whoever can read `data.base`.
+    pub assignment_key: PathBuf,
+    /// Append-only log of administrative actions, see `audit`.
+    pub audit_log: PathBuf,
+    /// Journal entries after which the whole database is written out and
+    /// the journal emptied, see `journal`.
+    pub compact_journal_every: usize,
+    pub backup: BackupConfig,
+    pub webhooks: WebhooksConfig,
+    pub log: LogConfig,
+    pub rate_limit: RateLimitConfig,
+    pub cors: CorsConfig,
+    pub security_headers: SecurityHeadersConfig,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: "127.0.0.1:8080".to_string(),
+            tls: None,
+            public_url: "http://127.0.0.1:8080".to_string(),
+            database: PathBuf::from("data.base"),
+            journal: PathBuf::from("data.journal"),
+            mail: MailConfig::default(),
+            assignment_key: PathBuf::from("santa.key"),
+            audit_log: PathBuf::from("audit.log"),
+            compact_journal_every: 100,
+            backup: BackupConfig::default(),
+            webhooks: WebhooksConfig::default(),
+            log: LogConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            cors: CorsConfig::default(),
+            security_headers: SecurityHeadersConfig::default(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Filter directives like `info` or `secret_santa_service=debug`.
+    /// `RUST_LOG` takes precedence when set.
+    pub level: String,
+    /// Print one JSON object per line instead of human readable text.
+    pub json: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: "info".to_string(),
+            json: false,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MailConfig {
+    /// Emails are not sent at all.
+    #[default]
+    Disabled,
+    /// Emails are appended to a file, or printed to stderr without a path.
+    /// Assignments are left out of them.
+    Log { path: Option<PathBuf> },
+    /// Emails are handed to an SMTP relay.
+    Smtp {
+        host: String,
+        #[serde(default = "default_smtp_port")]
+        port: u16,
+        from: String,
+    },
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+#[derive(serde::Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain.
+    pub cert: PathBuf,
+    /// PEM private key, PKCS#8 or RSA.
+    pub key: PathBuf,
+    /// Plain HTTP address that redirects every request to `public_url`.
+    #[serde(default)]
+    pub redirect_from: Option<String>,
+}
+
+/// Scheduled backups and the operator endpoints, see `backup`.
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct BackupConfig {
+    pub directory: PathBuf,
+    /// Hours between scheduled backups, 0 turns them off.
+    pub every_hours: u64,
+    /// Scheduled backups to keep, older ones are deleted.
+    pub keep: usize,
+    /// Bearer token for `/admin/backup` and `/admin/restore`. Without one
+    /// the endpoints are off.
+    pub token: Option<String>,
+}
+
+impl Default for BackupConfig {
+    fn default() -> BackupConfig {
+        BackupConfig {
+            directory: PathBuf::from("backups"),
+            every_hours: 24,
+            keep: 7,
+            token: None,
+        }
+    }
+}
+
+/// Outbound webhooks, see `webhooks`.
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    /// Hosts, as written in the URL, that webhooks may point to even though
+    /// they resolve to a loopback, private or link-local address. Any other
+    /// host must resolve to public addresses only.
+    pub allowed_hosts: Vec<String>,
+    /// Milliseconds before the first retry of a failed delivery, doubled
+    /// for every retry after it.
+    pub first_retry_ms: u64,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> WebhooksConfig {
+        WebhooksConfig {
+            allowed_hosts: Vec::new(),
+            first_retry_ms: 1000,
+        }
+    }
+}
+
+/// Token buckets per client IP, see `rate_limit`.
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Take the client IP from `Forwarded` or `X-Forwarded-For`. Only safe
+    /// behind a proxy that sets them.
+    pub trust_proxy: bool,
+    /// Joining, inviting and logging in, where guessing is worth trying.
+    pub join: Limit,
+    /// Every other POST.
+    pub write: Limit,
+    /// GET requests.
+    pub read: Limit,
+}
+
+#[derive(serde::Deserialize, Clone, Copy)]
+pub struct Limit {
+    /// Requests that may come at once.
+    pub burst: u32,
+    /// Requests allowed per minute once the burst is used up.
+    pub per_minute: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            enabled: true,
+            trust_proxy: false,
+            join: Limit {
+                burst: 5,
+                per_minute: 10,
+            },
+            write: Limit {
+                burst: 30,
+                per_minute: 60,
+            },
+            read: Limit {
+                burst: 60,
+                per_minute: 300,
+            },
+        }
+    }
+}
+
+/// Cross-origin access for browser frontends, see `security`.
+#[derive(serde::Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins like `https://santa.example.com`, or `*` for any. Empty
+    /// turns CORS off. Credentials can't be combined with `*`.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight answer, in seconds.
+    pub max_age: u32,
+}
+
+impl Default for CorsConfig {
+    fn default() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "OPTIONS"].map(String::from).to_vec(),
+            allow_credentials: false,
+            max_age: 86400,
+        }
+    }
+}
+
+/// Headers added to every response. An empty value leaves the header out.
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct SecurityHeadersConfig {
+    pub enabled: bool,
+    pub content_security_policy: String,
+    pub frame_options: String,
+    pub referrer_policy: String,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> SecurityHeadersConfig {
+        SecurityHeadersConfig {
+            enabled: true,
+            // The web UI has no scripts, styles or images of its own.
+            content_security_policy:
+                "default-src 'none'; form-action 'self'; frame-ancestors 'none'; base-uri 'none'"
+                    .to_string(),
+            frame_options: "DENY".to_string(),
+            referrer_policy: "same-origin".to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> std::io::Result<Config> {
+        let path = std::env::var("SANTA_CONFIG").unwrap_or_else(|_| "config.json".to_string());
+
+        match File::open(&path) {
+            Ok(file) => serde_json::from_reader(file).map_err(|err| {
+                let err = std::io::Error::from(err);
+                std::io::Error::new(
+                    err.kind(),
+                    format!("Failed to read config file {path}. {err}"),
+                )
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(std::io::Error::new(
+                err.kind(),
+                format!("Failed to open config file {path}. {err}"),
+            )),
+        }
+    }
+}
@@ -0,0 +1,451 @@
+//! Verifiable draws.
+//!
+//! A draw runs in two steps. First a random seed is picked and a commitment
+//! to it is published: the hex SHA-256 of the seed and the member names, one
+//! per line:
+//!
+//! ```text
+//! sha256("<seed hex>\n<member 1>\n<member 2>\n...")
+//! ```
+//!
+//! When some members must not gift others, those pairs follow after an
+//! empty line, giver and excluded member separated by a tab, sorted:
+//!
+//! ```text
+//! sha256("<seed hex>\n<member 1>\n...\n\n<giver>\t<excluded>\n...")
+//! ```
+//!
+//! Members can see it with `/groups/verify_draw`, exclusions included, and
+//! in the group summary.
+//! Only then does the draw run: members are ordered by
+//! `sha256("<seed hex>:<name>")` and each one gifts the next, the last one
+//! gifting the first. Once the exchange is over the seed is revealed, so
+//! anyone can redo both steps and check that the admin couldn't have chosen
+//! the outcome.
+//!
+//! A commitment is never thrown away silently. When members change before
+//! the draw, or an admin reopens the group to draw again, the old commitment
+//! moves to the group's draw history, which members see next to the current
+//! one. An admin redrawing until they like the result shows up there.
+
+use chrono::{DateTime, Duration, Utc};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tide::Request;
+
+use crate::render::reply;
+use crate::vault::Vault;
+use crate::{read_body, returnable_value, views, Group, State};
+
+/// When a group has no exchange date, the seed is revealed this long after
+/// the draw.
+const REVEAL_WITHOUT_EXCHANGE: Duration = Duration::days(31);
+
+/// Seeds tried before giving up on a draw that exclusions rule out.
+const MAX_ATTEMPTS: usize = 1000;
+
+/// Vault label the seed is sealed under, see `is_valid_name`.
+const SEED_LABEL: &str = "\0seed";
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct DrawProof {
+    pub commitment: String,
+    /// Member names in the order they were committed to.
+    pub members: Vec<String>,
+    /// The seed, sealed by `Vault` until it is revealed.
+    seed: String,
+    #[serde(default)]
+    pub committed_at: Option<DateTime<Utc>>,
+    /// Giver and excluded giftee pairs the draw avoids, sorted.
+    #[serde(default)]
+    pub exclusions: Vec<(String, String)>,
+}
+
+/// A commitment that was replaced before its draw ran, or a draw undone by
+/// reopening the group. The seed isn't kept.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct EarlierDraw {
+    pub commitment: String,
+    pub members: Vec<String>,
+    #[serde(default)]
+    pub exclusions: Vec<(String, String)>,
+    pub committed_at: Option<DateTime<Utc>>,
+    /// When the draw ran, `None` when it never did.
+    pub drawn_at: Option<DateTime<Utc>>,
+    pub discarded_at: DateTime<Utc>,
+    /// The admin who discarded it, `None` for the scheduler.
+    pub discarded_by: Option<String>,
+}
+
+impl DrawProof {
+    /// Picks a seed for a draw between `members` and commits to it.
+    ///
+    /// Seeds whose order pairs a giver with a giftee `exclusions` rules out
+    /// are thrown away before anything is committed, so verification stays
+    /// the same. Returns `None` when no seed worked out.
+    pub fn new(
+        group_name: &str,
+        members: Vec<String>,
+        exclusions: Vec<(String, String)>,
+        now: DateTime<Utc>,
+        vault: &Vault,
+    ) -> Option<DrawProof> {
+        let allowed = |giver: &String, giftee: &String| {
+            !exclusions
+                .iter()
+                .any(|(g, excluded)| g == giver && excluded == giftee)
+        };
+        for _ in 0..MAX_ATTEMPTS {
+            let mut seed = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut seed);
+            let seed = hex::encode(seed);
+
+            let order = order(&seed, &members);
+            if !pairs(&order).all(|(giver, giftee)| allowed(giver, giftee)) {
+                continue;
+            }
+            return Some(DrawProof {
+                commitment: commitment(&seed, &members, &exclusions),
+                members,
+                seed: vault.seal(group_name, SEED_LABEL, &seed),
+                committed_at: Some(now),
+                exclusions,
+            });
+        }
+        None
+    }
+
+    /// The order the committed members gift each other in.
+    pub fn order(&self, group_name: &str, vault: &Vault) -> Option<Vec<String>> {
+        let seed = vault.open(group_name, SEED_LABEL, &self.seed)?;
+        Some(order(&seed, &self.members))
+    }
+
+    /// Whether the commitment is for exactly these members and exclusions,
+    /// the latter sorted.
+    pub fn is_for<'a>(
+        &self,
+        members: impl Iterator<Item = &'a str>,
+        exclusions: &[(String, String)],
+    ) -> bool {
+        let mut members: Vec<&str> = members.collect();
+        let mut committed: Vec<&str> = self.members.iter().map(String::as_str).collect();
+        members.sort_unstable();
+        committed.sort_unstable();
+        members == committed && self.exclusions == exclusions
+    }
+
+    /// Turns the proof into a history entry.
+    pub fn discard(
+        self,
+        drawn_at: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+        by: Option<String>,
+    ) -> EarlierDraw {
+        EarlierDraw {
+            commitment: self.commitment,
+            members: self.members,
+            exclusions: self.exclusions,
+            committed_at: self.committed_at,
+            drawn_at,
+            discarded_at: now,
+            discarded_by: by,
+        }
+    }
+}
+
+fn commitment(seed: &str, members: &[String], exclusions: &[(String, String)]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(seed.as_bytes());
+    for name in members {
+        hasher.update(b"\n");
+        hasher.update(name.as_bytes());
+    }
+    // Nothing is added without exclusions, so older commitments still hold.
+    if !exclusions.is_empty() {
+        hasher.update(b"\n");
+    }
+    for (giver, excluded) in exclusions {
+        hasher.update(b"\n");
+        hasher.update(giver.as_bytes());
+        hasher.update(b"\t");
+        hasher.update(excluded.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Orders members by the hash of the seed and their name.
+fn order(seed: &str, members: &[String]) -> Vec<String> {
+    let mut keyed: Vec<(String, String)> = members
+        .iter()
+        .map(|name| {
+            let key = hex::encode(Sha256::digest(format!("{seed}:{name}").as_bytes()));
+            (key, name.clone())
+        })
+        .collect();
+    keyed.sort();
+    keyed.into_iter().map(|(_, name)| name).collect()
+}
+
+/// Giver and giftee pairs for members in gifting order.
+pub fn pairs(order: &[String]) -> impl Iterator<Item = (&String, &String)> {
+    order
+        .iter()
+        .zip(order.iter().cycle().skip(1))
+        .take(order.len())
+}
+
+fn reveal_at(group: &Group) -> Option<DateTime<Utc>> {
+    group
+        .exchange_date
+        .or_else(|| group.drawn_at.map(|at| at + REVEAL_WITHOUT_EXCHANGE))
+}
+
+/// Result of checking a draw against its commitment.
+pub struct Verification {
+    pub seed: String,
+    pub commitment_valid: bool,
+    pub assignments_valid: bool,
+}
+
+fn verify(group: &Group, proof: &DrawProof, vault: &Vault) -> Option<Verification> {
+    let seed = vault.open(&group.name, SEED_LABEL, &proof.seed)?;
+    let order = order(&seed, &proof.members);
+    let assignments_valid = group.people.len() == proof.members.len()
+        && pairs(&order).all(|(giver, giftee)| {
+            group
+                .people
+                .iter()
+                .find(|p| &p.name == giver)
+                .and_then(|p| vault.open(&group.name, &p.name, &p.santa_to))
+                .is_some_and(|santa_to| &santa_to == giftee)
+        });
+
+    Some(Verification {
+        commitment_valid: commitment(&seed, &proof.members, &proof.exclusions) == proof.commitment,
+        assignments_valid,
+        seed,
+    })
+}
+
+/// Shows a member the commitment of their group's draw, published before it
+/// runs, and once the exchange is over, the seed and whether the draw
+/// matches it. Earlier commitments of the group come along.
+pub async fn verify_draw(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Deserialize, Default)]
+    struct Data {
+        name: String,
+        group_name: String,
+    }
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+
+    let state = req.state();
+    let guard = state.database.lock().unwrap();
+    let Some(group) = guard.groups.values().find(|g| g.name == data.group_name) else {
+        return returnable_value(&req, "Group with that name does not exist", 400);
+    };
+    if !group.people.iter().any(|p| p.name == data.name) {
+        return returnable_value(&req, "Only group members can verify the draw", 403);
+    }
+    let proof = group.draw_proof.as_ref();
+    if proof.is_none() && group.draw_history.is_empty() {
+        return returnable_value(&req, "There was no verifiable draw in this group", 400);
+    }
+
+    let reveal_at = reveal_at(group).filter(|_| group.closed && proof.is_some());
+    let verification = match proof {
+        Some(proof) if reveal_at.is_some_and(|at| at <= Utc::now()) => {
+            match verify(group, proof, &state.vault) {
+                Some(verification) => Some(verification),
+                None => return returnable_value(&req, "Failed to read the draw seed", 500),
+            }
+        }
+        _ => None,
+    };
+
+    reply(
+        &req,
+        200,
+        &views::DrawVerification {
+            group_name: &group.name,
+            proof,
+            drawn_at: group.drawn_at.filter(|_| group.closed),
+            drawn: group.closed,
+            reveal_at,
+            verification: verification.as_ref(),
+            history: &group.draw_history,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    use crate::testing::TestApp;
+
+    async fn set_santas(app: &TestApp) -> String {
+        let body = json!({ "name": "alice", "group_name": "party" });
+        let (code, message) = app.post("/groups/set_santas", body).await;
+        assert_eq!(code, 200, "{message}");
+        message.as_str().unwrap().to_string()
+    }
+
+    async fn verify(app: &TestApp, name: &str) -> serde_json::Value {
+        let body = json!({ "name": name, "group_name": "party" });
+        let (code, message) = app.post("/groups/verify_draw", body).await;
+        assert_eq!(code, 200, "{message}");
+        message
+    }
+
+    #[async_std::test]
+    async fn commitment_is_published_before_the_draw() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob", "carol"]).await;
+
+        assert!(set_santas(&app)
+            .await
+            .starts_with("Draw commitment published"));
+        let committed = verify(&app, "bob").await;
+        assert_eq!(committed["drawn"], false);
+        assert_eq!(committed["seed"], serde_json::Value::Null);
+        {
+            let guard = app.state.database.lock().unwrap();
+            let group = guard.groups.values().next().unwrap();
+            assert!(!group.closed);
+            assert!(group.people.iter().all(|p| p.santa_to.is_empty()));
+        }
+
+        assert!(set_santas(&app)
+            .await
+            .starts_with("Secret santas are appointed"));
+        let drawn = verify(&app, "bob").await;
+        assert_eq!(drawn["drawn"], true);
+        assert_eq!(drawn["commitment"], committed["commitment"]);
+    }
+
+    #[async_std::test]
+    async fn new_members_need_a_new_commitment() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        set_santas(&app).await;
+        let first = verify(&app, "bob").await;
+
+        let body = json!({ "name": "carol", "group_name": "party" });
+        assert_eq!(app.post("/groups/join", body).await.0, 200);
+        assert!(set_santas(&app)
+            .await
+            .starts_with("Draw commitment published"));
+        let second = verify(&app, "carol").await;
+        assert_ne!(second["commitment"], first["commitment"]);
+        assert_eq!(second["members"].as_array().unwrap().len(), 3);
+    }
+
+    #[async_std::test]
+    async fn summary_shows_the_commitment_in_every_format() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        set_santas(&app).await;
+        let commitment = verify(&app, "bob").await["commitment"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        // Non-members get the summary.
+        let body = json!({ "name": "eve", "group_name": "party" });
+        for accept in ["text/plain", "application/json", "text/html", "text/csv"] {
+            let summary = app
+                .post_accepting("/groups/members", body.clone(), accept)
+                .await;
+            assert!(summary.contains(&commitment), "{accept}: {summary}");
+        }
+    }
+
+    #[async_std::test]
+    async fn reopened_draws_stay_in_the_history() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob", "carol"]).await;
+        app.draw("party", "alice").await;
+        let first = verify(&app, "bob").await;
+
+        let body = json!({ "name": "alice", "group_name": "party" });
+        assert_eq!(app.post("/groups/reopen", body).await.0, 200);
+        let reopened = verify(&app, "bob").await;
+        assert_eq!(reopened["commitment"], serde_json::Value::Null);
+        let history = reopened["history"].as_array().unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0]["commitment"], first["commitment"]);
+        assert_eq!(history[0]["drawn_at"], first["drawn_at"]);
+        assert_eq!(history[0]["discarded_by"], "alice");
+
+        app.draw("party", "alice").await;
+        let second = verify(&app, "bob").await;
+        assert_ne!(second["commitment"], first["commitment"]);
+        assert_eq!(second["history"].as_array().unwrap().len(), 1);
+        let body = json!({ "name": "bob", "group_name": "party" });
+        let text = app
+            .post_accepting("/groups/verify_draw", body, "text/plain")
+            .await;
+        assert!(text.contains("Earlier draws"), "{text}");
+        assert!(
+            text.contains(first["commitment"].as_str().unwrap()),
+            "{text}"
+        );
+    }
+
+    #[async_std::test]
+    async fn replaced_commitments_stay_in_the_history() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        set_santas(&app).await;
+        let first = verify(&app, "bob").await;
+
+        let body = json!({ "name": "carol", "group_name": "party" });
+        assert_eq!(app.post("/groups/join", body).await.0, 200);
+        set_santas(&app).await;
+        let history = verify(&app, "carol").await["history"].clone();
+        assert_eq!(history[0]["commitment"], first["commitment"]);
+        assert_eq!(history[0]["drawn_at"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn commitments_without_exclusions_are_unchanged() {
+        let members = ["alice".to_string(), "bob".to_string()];
+        let old = hex::encode(Sha256::digest(b"00ff\nalice\nbob"));
+        assert_eq!(super::commitment("00ff", &members, &[]), old);
+
+        let exclusions = [("alice".to_string(), "bob".to_string())];
+        let new = hex::encode(Sha256::digest(b"00ff\nalice\nbob\n\nalice\tbob"));
+        assert_eq!(super::commitment("00ff", &members, &exclusions), new);
+    }
+
+    #[async_std::test]
+    async fn exclusions_are_committed_to_and_shown() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob", "carol"]).await;
+        set_santas(&app).await;
+        let first = verify(&app, "bob").await;
+
+        let csv = "name,exclusions\nbob,carol\n";
+        let body = json!({ "name": "alice", "group_name": "party", "csv": csv });
+        assert_eq!(app.post("/groups/import", body).await.0, 200);
+        assert!(set_santas(&app)
+            .await
+            .starts_with("Draw commitment published"));
+        let second = verify(&app, "carol").await;
+        assert_ne!(second["commitment"], first["commitment"]);
+        assert_eq!(second["exclusions"], json!([["bob", "carol"]]));
+        assert_eq!(second["history"][0]["commitment"], first["commitment"]);
+
+        set_santas(&app).await;
+        let guard = app.state.database.lock().unwrap();
+        let bob = &guard.groups.values().next().unwrap().people[1];
+        let gifts_to = app.state.vault.open("party", "bob", &bob.santa_to);
+        assert_eq!(gifts_to.as_deref(), Some("alice"));
+    }
+}
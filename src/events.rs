@@ -0,0 +1,271 @@
+//! Group activity feed. Handlers publish events, and `/groups/events`
+//! streams the ones of a single group to browsers as Server-Sent Events.
+//!
+//! Events only say *that* something happened. Assignments never go through
+//! here, so subscribers can't learn who gifts whom. Events name members,
+//! so only members may follow them. Who is a member is taken from the
+//! `name` the subscriber gives, without a token, so this keeps out the
+//! curious rather than an attacker.
+//!
+//! Idle streams get a `keep_alive` event every `KEEP_ALIVE`, which browsers
+//! ignore unless they listen for it. That is how a stream notices its
+//! subscriber went away when the group is quiet.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_std::channel::{unbounded, Receiver, Sender};
+use async_std::future::timeout;
+use tide::{sse::Sender as SseSender, Request};
+
+use crate::State;
+
+#[cfg(not(test))]
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+#[cfg(test)]
+const KEEP_ALIVE: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum GroupEvent {
+    MemberJoined {
+        group_name: String,
+        name: String,
+    },
+    MemberQuit {
+        group_name: String,
+        name: String,
+    },
+    AdminChanged {
+        group_name: String,
+        name: String,
+        admin: bool,
+    },
+    MemberKicked {
+        group_name: String,
+        name: String,
+    },
+    /// The draw is committed to and runs next, see `draw`.
+    DrawCommitted {
+        group_name: String,
+        commitment: String,
+    },
+    DrawCompleted {
+        group_name: String,
+        /// Commitment to the draw seed, see `draw`.
+        commitment: String,
+    },
+    GroupReopened {
+        group_name: String,
+    },
+    GroupDeleted {
+        group_name: String,
+    },
+}
+
+impl GroupEvent {
+    pub fn group_name(&self) -> &str {
+        match self {
+            GroupEvent::MemberJoined { group_name, .. }
+            | GroupEvent::MemberQuit { group_name, .. }
+            | GroupEvent::AdminChanged { group_name, .. }
+            | GroupEvent::MemberKicked { group_name, .. }
+            | GroupEvent::DrawCommitted { group_name, .. }
+            | GroupEvent::DrawCompleted { group_name, .. }
+            | GroupEvent::GroupReopened { group_name }
+            | GroupEvent::GroupDeleted { group_name } => group_name,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GroupEvent::MemberJoined { .. } => "member_joined",
+            GroupEvent::MemberQuit { .. } => "member_quit",
+            GroupEvent::AdminChanged { .. } => "admin_changed",
+            GroupEvent::MemberKicked { .. } => "member_kicked",
+            GroupEvent::DrawCommitted { .. } => "draw_committed",
+            GroupEvent::DrawCompleted { .. } => "draw_completed",
+            GroupEvent::GroupReopened { .. } => "group_reopened",
+            GroupEvent::GroupDeleted { .. } => "group_deleted",
+        }
+    }
+}
+
+/// A stream and the group it follows.
+struct Subscriber {
+    group_name: String,
+    sender: Sender<GroupEvent>,
+}
+
+#[derive(Clone, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Events {
+    pub fn subscribe(&self, group_name: &str) -> Receiver<GroupEvent> {
+        let (sender, receiver) = unbounded();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+        subscribers.push(Subscriber {
+            group_name: group_name.to_string(),
+            sender,
+        });
+        receiver
+    }
+
+    /// Delivers the event to the live subscribers of its group and forgets
+    /// every one whose stream has gone away.
+    pub fn publish(&self, event: GroupEvent) {
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            if subscriber.group_name == event.group_name() {
+                subscriber.sender.try_send(event.clone()).is_ok()
+            } else {
+                !subscriber.sender.is_closed()
+            }
+        });
+    }
+}
+
+pub async fn stream(req: Request<State>, sender: SseSender) -> tide::Result<()> {
+    #[derive(serde::Deserialize, Default)]
+    #[serde(default)]
+    struct Query {
+        name: String,
+        group_name: String,
+    }
+    let Query { name, group_name } = req.query().unwrap_or_default();
+
+    let receiver = req.state().events.subscribe(&group_name);
+    let is_member = req
+        .state()
+        .database
+        .lock()
+        .unwrap()
+        .groups
+        .values()
+        .find(|g| g.name == group_name)
+        .map(|g| g.people.iter().any(|p| p.name == name));
+    let error = match is_member {
+        None => Some("There is no group with that name"),
+        Some(false) => Some("Only members of the group can follow its events"),
+        Some(true) => None,
+    };
+    if let Some(error) = error {
+        sender.send("error", error, None).await?;
+        return Ok(());
+    }
+
+    loop {
+        let event = match timeout(KEEP_ALIVE, receiver.recv()).await {
+            Ok(Ok(event)) => event,
+            Ok(Err(_)) => break,
+            // Fails once the subscriber is gone, which ends the stream.
+            Err(_) => {
+                sender.send("keep_alive", "", None).await?;
+                continue;
+            }
+        };
+        sender
+            .send(event.kind(), serde_json::to_string(&event)?, None)
+            .await?;
+        // The stream ends with the subscriber's membership.
+        match &event {
+            GroupEvent::GroupDeleted { .. } => break,
+            GroupEvent::MemberQuit { name: left, .. }
+            | GroupEvent::MemberKicked { name: left, .. }
+                if *left == name =>
+            {
+                break
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_std::future::timeout;
+    use serde_json::json;
+
+    use crate::testing::TestApp;
+
+    async fn stream(app: &TestApp, query: &str) -> String {
+        let mut res = app.get(&format!("/groups/events?{query}")).await;
+        timeout(Duration::from_secs(5), res.body_string())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[async_std::test]
+    async fn only_members_follow_events() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+
+        let body = stream(&app, "group_name=party&name=eve").await;
+        assert!(body.contains("event:error"), "{body}");
+        assert!(body.contains("Only members"), "{body}");
+        let body = stream(&app, "group_name=party").await;
+        assert!(body.contains("Only members"), "{body}");
+        let body = stream(&app, "group_name=other&name=alice").await;
+        assert!(body.contains("no group with that name"), "{body}");
+    }
+
+    #[async_std::test]
+    async fn streams_of_gone_subscribers_are_dropped() {
+        let app = TestApp::new();
+        app.group("party", &["alice"]).await;
+        app.group("quiet", &["bob"]).await;
+
+        let res = app.get("/groups/events?group_name=quiet&name=bob").await;
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        assert_eq!(app.state.events.subscribers.lock().unwrap().len(), 1);
+        drop(res);
+
+        // The next keep-alive finds the subscriber gone, and the next event
+        // of any group forgets the stream.
+        async_std::task::sleep(super::KEEP_ALIVE * 4).await;
+        let body = json!({ "name": "carol", "group_name": "party" });
+        assert_eq!(app.post("/groups/join", body).await.0, 200);
+        assert!(app.state.events.subscribers.lock().unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn stream_ends_when_the_subscriber_leaves() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+
+        let mut res = app.get("/groups/events?group_name=party&name=bob").await;
+        let body = async_std::task::spawn(async move { res.body_string().await });
+        // Gives the stream time to subscribe.
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        let (code, _) = app
+            .post(
+                "/groups/join",
+                json!({ "name": "carol", "group_name": "party" }),
+            )
+            .await;
+        assert_eq!(code, 200);
+        let (code, _) = app
+            .post(
+                "/groups/quit",
+                json!({ "name": "bob", "group_name": "party" }),
+            )
+            .await;
+        assert_eq!(code, 200);
+
+        let body = timeout(Duration::from_secs(5), body)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(body.contains("event:member_joined"), "{body}");
+        assert!(body.contains("event:member_quit"), "{body}");
+    }
+}
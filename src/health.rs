@@ -0,0 +1,84 @@
+//! Liveness and readiness probes for orchestrators.
+//!
+//! `/healthz` answers as long as the process serves requests. `/readyz`
+//! checks every component and answers 503 when one of them is down. Nothing
+//! in front of them takes the database lock, so a poisoned lock shows up in
+//! `/readyz` instead of failing both.
+
+use chrono::Utc;
+use serde_json::{json, Value};
+use tide::{Request, Response, StatusCode};
+
+use crate::State;
+
+pub async fn healthz(_req: Request<State>) -> tide::Result {
+    Ok(respond(true, json!({ "status": "ok" })))
+}
+
+pub async fn readyz(req: Request<State>) -> tide::Result {
+    let state = req.state();
+
+    // A handler that panicked with the lock held leaves the database
+    // poisoned, and every later request would fail.
+    let database = match state.database.lock() {
+        Ok(guard) => json!({ "status": "ok", "groups": guard.groups.len() }),
+        Err(_) => json!({ "status": "error", "error": "database lock is poisoned" }),
+    };
+
+    let storage = match state.journal.check_writable() {
+        Ok(()) => json!({ "status": "ok" }),
+        Err(err) => json!({ "status": "error", "error": err.to_string() }),
+    };
+
+    let last_tick = state.heartbeat.last();
+    let scheduler = if state.heartbeat.is_alive(Utc::now()) {
+        json!({ "status": "ok", "last_tick": last_tick })
+    } else {
+        json!({ "status": "error", "error": "scheduler is not running", "last_tick": last_tick })
+    };
+
+    let ready = [&database, &storage, &scheduler]
+        .iter()
+        .all(|component| component["status"] == "ok");
+    Ok(respond(
+        ready,
+        json!({
+            "status": if ready { "ok" } else { "unavailable" },
+            "components": {
+                "database": database,
+                "storage": storage,
+                "scheduler": scheduler
+            }
+        }),
+    ))
+}
+
+fn respond(ok: bool, body: Value) -> Response {
+    let mut res = Response::new(if ok {
+        StatusCode::Ok
+    } else {
+        StatusCode::ServiceUnavailable
+    });
+    res.set_body(body);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::testing::TestApp;
+
+    #[async_std::test]
+    async fn poisoned_database_is_not_ready_but_alive() {
+        let app = TestApp::new();
+        app.poison_database();
+
+        let mut res = app.get("/readyz").await;
+        assert_eq!(res.status(), 503);
+        let body: Value = res.body_json().await.unwrap();
+        assert_eq!(body["components"]["database"]["status"], "error");
+
+        assert_eq!(app.get("/healthz").await.status(), 200);
+    }
+}
@@ -0,0 +1,410 @@
+//! Write-ahead journal of changes to the database.
+//!
+//! Every change appends one line to `data.journal` (or the file set in the
+//! config) before the request is
+//! answered: what happened and the group as it is afterwards, or no group
+//! once it is deleted. A change whose line can't be written is rolled back
+//! and its request fails, see `persist`. Every so often the whole database
+//! is written to `data.base` and the journal starts over.
+//!
+//! At startup the snapshot is loaded and the journal replayed on top of it.
+//! Replaying an entry just puts its group in place, so entries that already
+//! made it into the snapshot, when a crash came between writing the snapshot
+//! and truncating the journal, do no harm.
+//!
+//! Entries carry the `data.base` format version of the build that wrote
+//! them, and groups from an older build go through the same migrations as
+//! the snapshot, see `migrations`. Entries from a newer build are refused.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use crate::{metrics, migrations, storage, DataBase, Group};
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    GroupCreated,
+    MemberJoined,
+    MembersImported,
+    ProfileChanged,
+    MemberQuit,
+    MemberKicked,
+    AdminChanged,
+    DrawCommitted,
+    DrawCompleted,
+    DrawFailed,
+    GroupReopened,
+    GroupDeleted,
+    DatesSet,
+    GiftBought,
+    WebhooksChanged,
+    RemindersSent,
+    TokenIssued,
+}
+
+#[derive(serde::Serialize)]
+struct EntryRef<'a> {
+    /// Format of `group`, see `migrations`.
+    version: u64,
+    at: DateTime<Utc>,
+    event: Event,
+    group_id: i8,
+    group: Option<&'a Group>,
+}
+
+#[derive(serde::Deserialize)]
+struct Entry {
+    /// Entries from before it was recorded are all version 1.
+    #[serde(default = "first_version")]
+    version: u64,
+    group_id: i8,
+    group: Option<Value>,
+}
+
+fn first_version() -> u64 {
+    1
+}
+
+struct Inner {
+    file: File,
+    /// Entries written since the last snapshot.
+    entries: usize,
+}
+
+#[derive(Clone)]
+pub struct Journal {
+    inner: Arc<Mutex<Inner>>,
+    path: Arc<PathBuf>,
+    /// The snapshot the journal is compacted into.
+    database_path: Arc<PathBuf>,
+    compact_every: usize,
+}
+
+impl Journal {
+    /// Opens the journal for appending. Call `replay` first.
+    pub fn open(path: &Path, database_path: &Path, compact_every: usize) -> io::Result<Journal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| {
+                io::Error::new(err.kind(), format!("Failed to open journal file. {err}"))
+            })?;
+        Ok(Journal {
+            inner: Arc::new(Mutex::new(Inner { file, entries: 0 })),
+            path: Arc::new(path.to_path_buf()),
+            database_path: Arc::new(database_path.to_path_buf()),
+            compact_every: compact_every.max(1),
+        })
+    }
+
+    /// Appends a change to the group with that name and compacts the journal
+    /// when it is due. `database` must already contain the change, except
+    /// for `GroupDeleted`: the group is removed here once its entry is
+    /// written, so a compaction can't put it back in the snapshot.
+    ///
+    /// An error means the change isn't saved. A failed compaction isn't an
+    /// error, the entry is in the journal and compaction is tried again with
+    /// the next one.
+    pub fn record(
+        &self,
+        database: &mut DataBase,
+        group_name: &str,
+        event: Event,
+    ) -> io::Result<()> {
+        let Some((&group_id, group)) = database.groups.iter().find(|(_, g)| g.name == group_name)
+        else {
+            return Ok(());
+        };
+        let entry = EntryRef {
+            version: migrations::CURRENT_VERSION,
+            at: Utc::now(),
+            event,
+            group_id,
+            group: match event {
+                Event::GroupDeleted => None,
+                _ => Some(group),
+            },
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        let mut inner = self.inner.lock().unwrap();
+        let start = Instant::now();
+        let len = inner.file.metadata()?.len();
+        let result = inner
+            .file
+            .write_all(&line)
+            .and_then(|_| inner.file.sync_data());
+        metrics::persisted(start.elapsed(), result.is_ok());
+        if let Err(err) = result {
+            // A partial line would otherwise end up in the middle of the
+            // journal once the next write works, and stop replay there.
+            if let Err(err) = inner.file.set_len(len) {
+                tracing::error!(error = %err, "Failed to drop a partial journal entry");
+            }
+            return Err(io::Error::new(
+                err.kind(),
+                format!("Failed to write to journal. {err}"),
+            ));
+        }
+
+        if let Event::GroupDeleted = event {
+            database.groups.remove(&group_id);
+        }
+        inner.entries += 1;
+        if inner.entries >= self.compact_every {
+            tracing::debug!(entries = inner.entries, "Compacting journal");
+            if let Err(err) =
+                storage::save(&self.database_path, database).and_then(|_| truncate(&mut inner))
+            {
+                tracing::error!(error = %err, "Failed to compact the journal");
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the snapshot with `database`, which isn't the one in memory.
+    /// The journal is emptied first, so its entries can't be replayed over
+    /// the new snapshot after a crash.
+    pub fn replace(&self, database: &DataBase) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        truncate(&mut inner)?;
+        storage::save(&self.database_path, database)
+    }
+
+    /// The database as the snapshot and the journal have it, that is
+    /// without changes whose `record` failed.
+    pub fn durable(&self) -> io::Result<DataBase> {
+        let _inner = self.inner.lock().unwrap();
+        let mut database = storage::load(&self.database_path)?;
+        replay(&self.path, &mut database)?;
+        Ok(database)
+    }
+
+    /// Writes a snapshot and empties the journal.
+    pub fn compact(&self, database: &DataBase) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        storage::save(&self.database_path, database)?;
+        truncate(&mut inner)
+    }
+
+    /// Makes every later write fail, like a full disk would.
+    #[cfg(test)]
+    pub fn fail_writes(&self) {
+        self.inner.lock().unwrap().file = File::open(self.path.as_ref()).unwrap();
+    }
+
+    /// Checks that the next snapshot can be written.
+    pub fn check_writable(&self) -> io::Result<()> {
+        storage::check_writable(&self.database_path)
+    }
+}
+
+/// Applies the journal to a freshly loaded snapshot. Returns the number of
+/// entries applied.
+#[tracing::instrument(name = "journal.replay", skip_all, err)]
+pub fn replay(path: &Path, database: &mut DataBase) -> io::Result<usize> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => {
+            return Err(io::Error::new(
+                err.kind(),
+                format!("Failed to open journal file. {err}"),
+            ))
+        }
+    };
+
+    let lines: Vec<String> = BufReader::new(file).lines().collect::<io::Result<_>>()?;
+    let mut applied = 0;
+    for (index, line) in lines.iter().enumerate() {
+        let entry: Entry = match serde_json::from_str(line) {
+            Ok(entry) => entry,
+            // The last line may have been cut short by a crash while it was
+            // written. Its request never got an answer, so it's safe to drop.
+            Err(err) if index + 1 == lines.len() => {
+                tracing::warn!(error = %err, "Dropping incomplete last journal entry");
+                break;
+            }
+            Err(err) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Journal entry {} is corrupt. {err}", index + 1),
+                ))
+            }
+        };
+        let group = match entry.group {
+            Some(group) => Some(
+                upgrade(entry.version, entry.group_id, group).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Journal entry {} can't be read. {err}", index + 1),
+                    )
+                })?,
+            ),
+            None => None,
+        };
+        match group {
+            Some(group) => database.groups.insert(entry.group_id, group),
+            None => database.groups.remove(&entry.group_id),
+        };
+        applied += 1;
+    }
+
+    if applied > 0 {
+        tracing::info!(entries = applied, "Replayed journal");
+    }
+    Ok(applied)
+}
+
+/// Brings a group written in format `version` to the current one, through
+/// the same steps as a whole `data.base` file.
+fn upgrade(version: u64, group_id: i8, group: Value) -> Result<Group, String> {
+    let key = group_id.to_string();
+    let file = json!({ "version": version, "data": { "groups": { &key: group } } });
+    let mut data = migrations::migrate(file)?;
+    serde_json::from_value(data["groups"][&key].take()).map_err(|err| err.to_string())
+}
+
+fn truncate(inner: &mut Inner) -> io::Result<()> {
+    inner
+        .file
+        .set_len(0)
+        .and_then(|_| inner.file.sync_all())
+        .map_err(|err| io::Error::new(err.kind(), format!("Failed to truncate journal. {err}")))?;
+    inner.entries = 0;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use serde_json::{json, Value};
+
+    use super::replay;
+    use crate::testing::TestApp;
+    use crate::{migrations, storage, DataBase};
+
+    fn replayed(dir: &Path) -> std::io::Result<DataBase> {
+        let mut database = storage::load(&dir.join("data.base"))?;
+        replay(&dir.join("data.journal"), &mut database)?;
+        Ok(database)
+    }
+
+    fn journal_lines(dir: &Path) -> Vec<String> {
+        let journal = fs::read_to_string(dir.join("data.journal")).unwrap();
+        journal.lines().map(str::to_string).collect()
+    }
+
+    #[async_std::test]
+    async fn replay_puts_back_every_change() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob", "carol"]).await;
+        app.draw("party", "alice").await;
+        assert!(storage::load(&app.dir().join("data.base"))
+            .unwrap()
+            .groups
+            .is_empty());
+
+        let database = replayed(app.dir()).unwrap();
+        let group = database.groups.values().next().unwrap();
+        assert_eq!(group.people.len(), 3);
+        assert!(group.closed);
+        assert!(group.people.iter().all(|p| !p.santa_to.is_empty()));
+    }
+
+    #[async_std::test]
+    async fn only_a_cut_short_last_entry_is_dropped() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        let lines = journal_lines(app.dir());
+        let journal = app.dir().join("data.journal");
+
+        let cut = &lines[1][..lines[1].len() / 2];
+        fs::write(&journal, format!("{}\n{cut}", lines[0])).unwrap();
+        let database = replayed(app.dir()).unwrap();
+        assert_eq!(database.groups.values().next().unwrap().people.len(), 1);
+
+        fs::write(&journal, format!("{}\n{cut}\n{}\n", lines[0], lines[1])).unwrap();
+        let err = replayed(app.dir()).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[async_std::test]
+    async fn entries_carry_the_format_version() {
+        let app = TestApp::new();
+        app.group("party", &["alice"]).await;
+        let mut entry: Value = serde_json::from_str(&journal_lines(app.dir())[0]).unwrap();
+        assert_eq!(entry["version"], json!(migrations::CURRENT_VERSION));
+
+        // Entries from before the version was written are read as version 1.
+        entry.as_object_mut().unwrap().remove("version");
+        fs::write(app.dir().join("data.journal"), format!("{entry}\n")).unwrap();
+        assert_eq!(replayed(app.dir()).unwrap().groups.len(), 1);
+
+        entry["version"] = json!(migrations::CURRENT_VERSION + 1);
+        fs::write(app.dir().join("data.journal"), format!("{entry}\n")).unwrap();
+        assert!(replayed(app.dir()).is_err());
+    }
+
+    #[async_std::test]
+    async fn compaction_moves_the_journal_into_the_snapshot() {
+        let app = TestApp::with_config(|config| config.compact_journal_every = 2);
+        app.group("party", &["alice", "bob"]).await;
+
+        assert!(journal_lines(app.dir()).is_empty());
+        let snapshot = storage::load(&app.dir().join("data.base")).unwrap();
+        assert_eq!(snapshot.groups.values().next().unwrap().people.len(), 2);
+
+        app.group("other", &["carol"]).await;
+        assert_eq!(journal_lines(app.dir()).len(), 1);
+        assert_eq!(replayed(app.dir()).unwrap().groups.len(), 2);
+    }
+
+    #[async_std::test]
+    async fn deleted_groups_stay_deleted_after_compaction() {
+        let app = TestApp::with_config(|config| config.compact_journal_every = 2);
+        app.group("party", &["alice"]).await;
+        let body = json!({ "name": "alice", "group_name": "party" });
+        let (code, message) = app.post("/groups/delete", body).await;
+        assert_eq!(code, 200, "{message}");
+
+        assert!(journal_lines(app.dir()).is_empty());
+        assert!(replayed(app.dir()).unwrap().groups.is_empty());
+        assert!(app.state.database.lock().unwrap().groups.is_empty());
+    }
+
+    #[async_std::test]
+    async fn unsaved_changes_are_rolled_back() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        app.state.journal.fail_writes();
+
+        let body = json!({ "name": "carol", "group_name": "party" });
+        let (code, _) = app.post("/groups/join", body).await;
+        assert_eq!(code, 500);
+        for _ in 0..2 {
+            let body = json!({ "name": "alice", "group_name": "party" });
+            assert_eq!(app.post("/groups/set_santas", body).await.0, 500);
+        }
+
+        let guard = app.state.database.lock().unwrap();
+        let group = guard.groups.values().next().unwrap();
+        assert_eq!(group.people.len(), 2);
+        assert!(group.draw_proof.is_none());
+        assert!(!group.closed);
+        drop(guard);
+        assert_eq!(journal_lines(app.dir()).len(), 2);
+    }
+}
@@ -0,0 +1,152 @@
+//! Structured logging through `tracing`, and a middleware that logs every
+//! request with its method, path, status, latency, acting user and group.
+
+use std::{io::IsTerminal, time::Instant};
+
+use tide::{http::Method, Body, Middleware, Next, Request};
+use tracing::{info_span, Instrument};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LogConfig;
+use crate::State;
+
+pub fn init(config: &LogConfig) -> std::io::Result<()> {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => EnvFilter::try_new(directives),
+        Err(_) => EnvFilter::try_new(&config.level),
+    }
+    .map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Bad log level. {err}"),
+        )
+    })?;
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    let result = if config.json {
+        builder.json().with_current_span(true).try_init()
+    } else {
+        builder.try_init()
+    };
+    result.map_err(|err| std::io::Error::other(err.to_string()))
+}
+
+/// Who a request acts as and on which group, taken from the same fields the
+/// handlers read.
+#[derive(serde::Deserialize, Default)]
+struct Subject {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    group_name: String,
+}
+
+/// Probes polled every few seconds. They aren't logged, and answer even
+/// when the rest of the service is stuck.
+const PROBES: [&str; 2] = ["/healthz", "/readyz"];
+
+/// Longer bodies aren't read for the subject, so large uploads such as
+/// `/admin/restore` aren't held in memory twice.
+const MAX_SUBJECT_BODY: usize = 64 * 1024;
+
+pub struct RequestLog;
+
+#[tide::utils::async_trait]
+impl Middleware<State> for RequestLog {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if PROBES.contains(&req.url().path()) {
+            return Ok(next.run(req).await);
+        }
+        let start = Instant::now();
+        let subject = subject(&mut req).await;
+
+        let span = info_span!(
+            "request",
+            method = %req.method(),
+            path = req.url().path(),
+            user = Some(subject.name.as_str()).filter(|name| !name.is_empty()),
+            group = Some(subject.group_name.as_str()).filter(|name| !name.is_empty()),
+        );
+        let res = next.run(req).instrument(span.clone()).await;
+
+        let status = u16::from(res.status());
+        let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+        span.in_scope(|| {
+            if status >= 500 {
+                tracing::error!(status, latency_ms, "request failed");
+            } else {
+                tracing::info!(status, latency_ms, "request handled");
+            }
+        });
+        Ok(res)
+    }
+}
+
+/// Reads the subject from the query, the body or the web UI cookie. The
+/// body is put back for the handler. Bodies of unknown length or over
+/// `MAX_SUBJECT_BODY` are left alone.
+async fn subject(req: &mut Request<State>) -> Subject {
+    let mut subject: Subject = req.query().unwrap_or_default();
+
+    if req.method() == Method::Post
+        && (subject.name.is_empty() || subject.group_name.is_empty())
+        && req.len().is_some_and(|len| len <= MAX_SUBJECT_BODY)
+    {
+        let content_type = req.content_type();
+        let body = req.body_bytes().await.unwrap_or_default();
+        if let Some(found) = serde_json::from_slice::<Subject>(&body)
+            .ok()
+            .or_else(|| serde_urlencoded::from_bytes(&body).ok())
+        {
+            if subject.name.is_empty() {
+                subject.name = found.name;
+            }
+            if subject.group_name.is_empty() {
+                subject.group_name = found.group_name;
+            }
+        }
+        let mut body = Body::from_bytes(body);
+        if let Some(mime) = content_type {
+            body.set_mime(mime);
+        }
+        req.set_body(body);
+    }
+
+    if subject.name.is_empty() {
+        if let Some(cookie) = req.cookie(crate::web::NAME_COOKIE) {
+            subject.name = cookie.value().to_string();
+        }
+    }
+
+    subject
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::testing::TestApp;
+
+    #[async_std::test]
+    async fn requests_are_logged_without_the_database() {
+        let app = TestApp::new();
+        app.poison_database();
+
+        let (code, message) = app.post("/", json!({ "name": "bob" })).await;
+        assert_eq!(code, 200);
+        assert_eq!(message, "Hello bob!");
+    }
+
+    #[async_std::test]
+    async fn large_bodies_reach_the_handler_unread() {
+        let app = TestApp::new();
+        let padding = "x".repeat(super::MAX_SUBJECT_BODY);
+        let body = json!({ "name": "bob", "padding": padding });
+        let (code, message) = app.post("/", body).await;
+        assert_eq!(code, 200);
+        assert_eq!(message, "Hello bob!");
+    }
+}
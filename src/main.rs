@@ -1,15 +1,48 @@
+use chrono::{DateTime, NaiveDate, Utc};
 use std::{
     collections::HashMap,
-    fs::File,
     sync::{Arc, Mutex},
 };
-use tide::prelude::*;
 use tide::Request;
 
-#[derive(serde::Serialize, serde::Deserialize)]
-struct QueryData {
-    json: bool,
-}
+mod audit;
+mod auth;
+mod backup;
+mod calendar;
+mod config;
+mod draw;
+mod events;
+mod health;
+mod journal;
+mod logging;
+mod metrics;
+mod migrations;
+mod notify;
+mod profile;
+mod rate_limit;
+mod render;
+mod roster;
+mod scheduler;
+mod security;
+mod storage;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod vault;
+mod views;
+mod web;
+mod webhooks;
+
+use audit::{Action, AuditLog};
+use config::{BackupConfig, Config};
+use draw::{DrawProof, EarlierDraw};
+use events::{Events, GroupEvent};
+use journal::{Event, Journal};
+use notify::Mailer;
+use profile::{Contact, Privacy};
+use render::{reply, Message};
+use vault::Vault;
+use webhooks::{Webhook, Webhooks};
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -21,8 +54,30 @@ enum Access {
 #[derive(serde::Serialize, serde::Deserialize)]
 struct Person {
     name: String,
+    /// Sealed by `Vault`, empty before the draw.
     santa_to: String,
     access: Access,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    gift_bought: bool,
+    /// Members this person must not be drawn to gift.
+    #[serde(default)]
+    exclusions: Vec<String>,
+    /// Shown next to the name, see `profile`.
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    contact: Contact,
+    #[serde(default)]
+    privacy: Privacy,
+    /// Shipping address sealed by `Vault`, only ever shown to this
+    /// person's santa, see `profile`.
+    #[serde(default)]
+    address: Option<String>,
+    /// Hex SHA-256 of the member's token, see `auth`.
+    #[serde(default)]
+    token_hash: Option<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -30,6 +85,185 @@ struct Group {
     name: String,
     people: Vec<Person>,
     closed: bool,
+    #[serde(default)]
+    webhooks: Vec<Webhook>,
+    #[serde(default)]
+    draw_date: Option<DateTime<Utc>>,
+    #[serde(default)]
+    exchange_date: Option<DateTime<Utc>>,
+    /// Whether `draw_date` was set as a plain date, which calendars show as
+    /// a whole day, see `calendar`.
+    #[serde(default)]
+    draw_all_day: bool,
+    /// Like `draw_all_day`, for `exchange_date`.
+    #[serde(default)]
+    exchange_all_day: bool,
+    /// Scheduled reminders already sent, see `scheduler`.
+    #[serde(default)]
+    reminders_sent: Vec<String>,
+    /// When set, the scheduler runs the draw at this time.
+    #[serde(default)]
+    auto_draw_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    drawn_by: Option<DrawOrigin>,
+    #[serde(default)]
+    drawn_at: Option<DateTime<Utc>>,
+    /// Commitment to the seed of the last draw, see `draw`.
+    #[serde(default)]
+    draw_proof: Option<DrawProof>,
+    /// Commitments replaced or reopened before `draw_proof`, oldest first.
+    #[serde(default)]
+    draw_history: Vec<EarlierDraw>,
+    /// Tells the group apart from earlier ones with the same name, in the
+    /// audit log. `None` for groups created before it was recorded.
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(tag = "by", rename_all = "snake_case")]
+enum DrawOrigin {
+    /// An admin called `/groups/set_santas`.
+    Admin { name: String },
+    /// The scheduler ran the draw at `auto_draw_at`.
+    System,
+}
+
+/// What `Group::draw` did.
+enum DrawStep {
+    /// Published the commitment to the seed of the coming draw.
+    Committed(String),
+    /// Ran the draw committed to earlier.
+    Drawn(String),
+}
+
+impl Group {
+    /// Assigns every member a person to gift and closes the group, from the
+    /// commitment published earlier. When there is none for the current
+    /// members, publishes one instead and leaves the draw for the next
+    /// call, so members always get to see the commitment first.
+    fn draw(
+        &mut self,
+        origin: DrawOrigin,
+        now: DateTime<Utc>,
+        vault: &Vault,
+    ) -> Result<DrawStep, &'static str> {
+        if self.closed {
+            return Err("Group is closed, reopen it to draw again");
+        }
+        if self.people.len() < 2 {
+            metrics::draw_failed("Not enough group members");
+            return Err("Not enough group members");
+        }
+        let exclusions = self.exclusions();
+        let order = self
+            .draw_proof
+            .as_ref()
+            .filter(|proof| proof.is_for(self.people.iter().map(|p| p.name.as_str()), &exclusions))
+            .and_then(|proof| proof.order(&self.name, vault));
+        let (Some(order), Some(proof)) = (order, &self.draw_proof) else {
+            let members = self.people.iter().map(|p| p.name.clone()).collect();
+            let Some(proof) = DrawProof::new(&self.name, members, exclusions, now, vault) else {
+                metrics::draw_failed("Exclusions leave no possible draw");
+                return Err("Exclusions leave no possible draw");
+            };
+            let commitment = proof.commitment.clone();
+            if let Some(stale) = self.draw_proof.replace(proof) {
+                let by = match origin {
+                    DrawOrigin::Admin { name } => Some(name),
+                    DrawOrigin::System => None,
+                };
+                self.draw_history.push(stale.discard(None, now, by));
+            }
+            return Ok(DrawStep::Committed(commitment));
+        };
+        let commitment = proof.commitment.clone();
+
+        for (giver, giftee) in draw::pairs(&order) {
+            let sealed = vault.seal(&self.name, giver, giftee);
+            if let Some(person) = self.people.iter_mut().find(|p| &p.name == giver) {
+                person.santa_to = sealed;
+            }
+        }
+        metrics::draw_completed(match origin {
+            DrawOrigin::Admin { .. } => "admin",
+            DrawOrigin::System => "system",
+        });
+        self.closed = true;
+        self.auto_draw_at = None;
+        self.drawn_by = Some(origin);
+        self.drawn_at = Some(now);
+        Ok(DrawStep::Drawn(commitment))
+    }
+
+    /// Who each member must not gift, as giver and giftee pairs, sorted.
+    /// Exclusions of people no longer in the group are left out.
+    fn exclusions(&self) -> Vec<(String, String)> {
+        let mut exclusions: Vec<(String, String)> = self
+            .people
+            .iter()
+            .flat_map(|giver| {
+                giver
+                    .exclusions
+                    .iter()
+                    .filter(|excluded| self.people.iter().any(|p| &p.name == *excluded))
+                    .map(|excluded| (giver.name.clone(), excluded.clone()))
+            })
+            .collect();
+        exclusions.sort();
+        exclusions.dedup();
+        exclusions
+    }
+
+    /// Removes `member` on behalf of the admin `name`.
+    fn kick(&mut self, name: &str, member: &str) -> Result<(), (&'static str, u16)> {
+        if !self.is_admin(name) {
+            return Err(("Only the administrator can remove members", 403));
+        }
+        if name == member {
+            return Err(("Use /groups/quit to leave the group", 400));
+        }
+        if self.closed {
+            return Err(("Reopen the group before removing members", 400));
+        }
+        let index = self
+            .people
+            .iter()
+            .position(|p| p.name == member)
+            .ok_or(("There is no such member in the group", 400))?;
+        self.people.remove(index);
+        Ok(())
+    }
+
+    /// Throws away the draw on behalf of the admin `name`, so members can
+    /// change and the draw can be run again.
+    fn reopen(&mut self, name: &str, now: DateTime<Utc>) -> Result<(), (&'static str, u16)> {
+        if !self.is_admin(name) {
+            return Err(("Only the administrator can reopen the group", 403));
+        }
+        if !self.closed {
+            return Err(("Group is not closed", 400));
+        }
+        for person in &mut self.people {
+            person.santa_to.clear();
+            person.gift_bought = false;
+        }
+        if let Some(proof) = self.draw_proof.take() {
+            let discarded = proof.discard(self.drawn_at, now, Some(name.to_string()));
+            self.draw_history.push(discarded);
+        }
+        self.closed = false;
+        self.drawn_by = None;
+        self.drawn_at = None;
+        self.reminders_sent.clear();
+        Ok(())
+    }
+
+    fn is_admin(&self, name: &str) -> bool {
+        self.people
+            .iter()
+            .any(|p| p.name == name && matches!(p.access, Access::Admin))
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -37,46 +271,111 @@ struct DataBase {
     groups: HashMap<i8, Group>,
 }
 
+#[derive(Clone)]
+struct State {
+    database: Arc<Mutex<DataBase>>,
+    events: Events,
+    webhooks: Webhooks,
+    mailer: Mailer,
+    vault: Vault,
+    audit: AuditLog,
+    heartbeat: scheduler::Heartbeat,
+    journal: Journal,
+    backup: Arc<BackupConfig>,
+}
+
+impl State {
+    /// Announces a group event to live streams and to the webhooks the
+    /// group has registered.
+    fn publish(&self, database: &DataBase, event: GroupEvent) {
+        let webhooks = database
+            .groups
+            .values()
+            .find(|g| g.name == event.group_name())
+            .map_or(&[][..], |group| &group.webhooks);
+        self.announce(webhooks, event);
+    }
+
+    /// Like `publish`, for a group that is no longer in the database.
+    fn announce(&self, webhooks: &[Webhook], event: GroupEvent) {
+        self.webhooks.send(webhooks, &event);
+        self.events.publish(event);
+    }
+
+    /// Loads the database, replays the journal on top of it and sets up
+    /// every component.
+    fn open(config: &Config) -> std::io::Result<State> {
+        let vault = Vault::load_or_create(&config.assignment_key)?;
+        let mut database = storage::load(&config.database)?;
+        journal::replay(&config.journal, &mut database)?;
+        vault.seal_plaintext(&mut database);
+        let journal = Journal::open(
+            &config.journal,
+            &config.database,
+            config.compact_journal_every,
+        )?;
+        journal.compact(&database)?;
+
+        Ok(State {
+            database: Arc::new(Mutex::new(database)),
+            events: Events::default(),
+            webhooks: Webhooks::start(&config.webhooks),
+            mailer: Mailer::from_config(config, vault.clone()),
+            vault,
+            audit: AuditLog::new(config.audit_log.clone()),
+            heartbeat: scheduler::Heartbeat::default(),
+            journal,
+            backup: Arc::new(config.backup.clone()),
+        })
+    }
+}
+
 #[async_std::main]
 async fn main() -> tide::Result<()> {
-    let database = match File::open("data.base") {
-        Ok(file) => serde_json::from_reader(file).map_err(|err| {
-            let err = std::io::Error::from(err);
-            std::io::Error::new(
-                err.kind(),
-                format!("Failed to read from database file. {err}"),
-            )
-        })?,
-        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
-            eprintln!("Database file not found. Creating one");
-
-            let file = File::create("data.base").map_err(|err| {
-                std::io::Error::new(err.kind(), format!("Failed to create database file. {err}"))
-            })?;
-            let database = DataBase {
-                groups: HashMap::new(),
-            };
-            serde_json::to_writer(file, &database).map_err(|err| {
-                let err = std::io::Error::from(err);
-                std::io::Error::new(
-                    err.kind(),
-                    format!("Failed to write to database file. {err}"),
-                )
-            })?;
-
-            database
-        }
-        Err(err) => {
-            panic!("Failed to open database file. {err}");
+    let config = Config::load()?;
+    logging::init(&config.log)?;
+
+    let state = State::open(&config)?;
+    scheduler::start(state.clone());
+    backup::start(state.clone());
+    let app = app(state, &config)?;
+    match &config.tls {
+        None => app.listen(config.listen.as_str()).await?,
+        Some(tls) => {
+            if let Some(redirect_from) = &tls.redirect_from {
+                tls::redirect(redirect_from.clone(), config.public_url.clone());
+            }
+            app.listen(tls::listener(&config.listen, tls)).await?
         }
-    };
+    }
 
-    let state = Arc::new(Mutex::new(database));
+    tracing::info!("Done");
+    Ok(())
+}
+
+/// The service with its middleware and routes.
+fn app(state: State, config: &Config) -> std::io::Result<tide::Server<State>> {
     let mut app = tide::with_state(state);
+    app.with(logging::RequestLog);
+    if config.security_headers.enabled {
+        app.with(security::SecurityHeaders::new(
+            config.security_headers.clone(),
+        ));
+    }
+    if let Some(cors) = security::cors(config)? {
+        app.with(cors);
+    }
+    if config.rate_limit.enabled {
+        app.with(rate_limit::RateLimiter::new(config.rate_limit.clone()));
+    }
+    // Last, so requests answered by the middleware above aren't counted per
+    // path, see `metrics::RequestMetrics`.
+    app.with(metrics::RequestMetrics);
 
     app.at("/").get(index);
     app.at("/").post(index);
     app.at("/to-who-gift").get(get_gifted);
+    app.at("/to-who-gift").post(get_gifted);
     app.at("/groups/list").get(get_groups);
     app.at("/groups/create").post(create_group);
     app.at("/groups/join").post(join_group);
@@ -86,615 +385,1351 @@ async fn main() -> tide::Result<()> {
     app.at("/groups/quit").post(quit_group);
     app.at("/groups/delete").post(delete_group);
     app.at("/groups/set_santas").post(set_santas);
+    app.at("/groups/kick").post(kick);
+    app.at("/groups/reopen").post(reopen);
+    app.at("/groups/profile").post(profile::update);
+    app.at("/groups/token").post(auth::resend);
+    app.at("/groups/import").post(roster::import);
+    app.at("/groups/export").post(roster::export);
+    app.at("/groups/audit").post(audit::list);
+    app.at("/groups/audit.jsonl").post(audit::export);
+    app.at("/groups/invite").post(invite);
+    app.at("/groups/remind").post(remind);
+    app.at("/groups/set_dates").post(set_dates);
+    app.at("/groups/gift_bought").post(gift_bought);
+    app.at("/groups/verify_draw").post(draw::verify_draw);
+    app.at("/groups/calendar.ics").get(calendar::group);
+    app.at("/users/calendar.ics").get(calendar::user);
+    app.at("/groups/events")
+        .get(tide::sse::endpoint(events::stream));
+    app.at("/groups/webhooks").post(webhooks::list);
+    app.at("/groups/webhooks/add").post(webhooks::add);
+    app.at("/groups/webhooks/remove").post(webhooks::remove);
+    app.at("/metrics").get(metrics::endpoint);
+    app.at("/healthz").get(health::healthz);
+    app.at("/readyz").get(health::readyz);
+    app.at("/admin/backup").get(backup::download);
+    app.at("/admin/restore").post(backup::restore);
+    app.at("/ui").get(web::home);
+    app.at("/ui/group").get(web::group);
+    app.at("/ui/login").post(web::login);
+    app.at("/ui/logout").post(web::logout);
     app.at("/terminate")
-        .get(|request: tide::Request<Arc<Mutex<DataBase>>>| async move {
+        .get(|request: tide::Request<State>| async move {
             let state = request.state();
-            std::fs::write(
-                "data.base",
-                serde_json::to_string(state).unwrap().as_bytes(),
-            )?;
+            state.journal.compact(&state.database.lock().unwrap())?;
             std::process::exit(0);
             #[allow(unreachable_code)]
             Ok("done")
         });
-    app.listen("127.0.0.1:8080").await?; // Your ip address
-
-    println!("Done");
-    Ok(())
+    Ok(app)
 }
 
-async fn get_gifted(mut req: Request<Arc<Mutex<DataBase>>>) -> tide::Result {
-    #[derive(serde::Serialize, serde::Deserialize)]
+async fn get_gifted(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
     struct Data {
         name: String,
         group_name: String,
+        #[serde(default)]
+        token: String,
     }
-    let data: Data = req.body_json().await.unwrap_or(Data {
-        name: String::new(),
-        group_name: String::new(),
-    });
-
-    let QueryData { json } = req.query().unwrap_or(QueryData { json: false });
+    let data: Data = read_body(&mut req).await;
 
-    if data.name == "" || data.group_name == "" {
-        return returnable_value("Bad data", json, 400);
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
     }
 
     let state = req.state();
-    let guard = state.lock().unwrap();
+    let guard = state.database.lock().unwrap();
     let mut groups = guard.groups.iter();
 
     match groups.find(|i| i.1.name == data.group_name) {
         Some(g) => {
             if !g.1.closed {
-                return returnable_value("Given group is not closed", json, 400);
+                return returnable_value(&req, "Given group is not closed", 400);
             }
             let mut people = g.1.people.iter();
             match people.find(|person| person.name == data.name) {
-                Some(p) => {
-                    if json {
-                        return Ok(json!({
-                            "code": 200,
-                            "message": {
-                                "gifted": p.santa_to
-                            }
-                        })
-                        .into());
-                    } else {
-                        return Ok(format!("You secret santa to - {}", p.santa_to).into());
-                    }
-                }
-                None => {
-                    return returnable_value("There is no such person in given group", json, 400);
+                Some(p) if !p.has_token(&data.token) => {
+                    returnable_value(&req, "Wrong or missing token", 403)
                 }
+                Some(p) => match state.vault.open(&g.1.name, &p.name, &p.santa_to) {
+                    Some(santa_to) => {
+                        let address =
+                            g.1.people
+                                .iter()
+                                .find(|person| person.name == santa_to)
+                                .and_then(|giftee| giftee.address(&g.1.name, &state.vault));
+                        reply(
+                            &req,
+                            200,
+                            &views::Gifted {
+                                santa_to: &santa_to,
+                                address: address.as_deref(),
+                            },
+                        )
+                    }
+                    None => returnable_value(&req, "Failed to read your assignment", 500),
+                },
+                None => returnable_value(&req, "There is no such person in given group", 400),
             }
         }
-        None => {
-            return returnable_value("There is no group with that name", json, 400);
-        }
+        None => returnable_value(&req, "There is no group with that name", 400),
     }
 }
 
-async fn set_santas(mut req: Request<Arc<Mutex<DataBase>>>) -> tide::Result {
-    #[derive(serde::Serialize, serde::Deserialize)]
+async fn set_santas(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
     struct Data {
         name: String,
         group_name: String,
     }
-    let data: Data = req.body_json().await.unwrap_or(Data {
-        name: String::new(),
-        group_name: String::new(),
-    });
-
-    let QueryData { json } = req.query().unwrap_or(QueryData { json: false });
+    let data: Data = read_body(&mut req).await;
 
-    if data.name == "" || data.group_name == "" {
-        return returnable_value("Bad data", json, 400);
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
     }
 
     let state = req.state();
-    let mut guard = state.lock().unwrap();
+    let mut guard = state.database.lock().unwrap();
 
     if !is_person_exist(&guard.groups, &data.name) {
-        return returnable_value("Person does not exist", json, 405);
+        return returnable_value(&req, "Person does not exist", 405);
     }
 
+    let created_at;
     let mut groups = guard.groups.iter_mut();
 
-    match groups.find(|i| i.1.name == data.group_name) {
+    let step = match groups.find(|i| i.1.name == data.group_name) {
         None => {
-            return returnable_value("Group with that name does not exist", json, 400);
+            return returnable_value(&req, "Group with that name does not exist", 400);
         }
         Some(i) => {
-            match i
-                .1
-                .people
-                .iter()
-                .find(|j| j.name == data.name)
-                .unwrap()
-                .access
-            {
+            let Some(person) = i.1.people.iter().find(|p| p.name == data.name) else {
+                return returnable_value(&req, "You are not a member of this group", 403);
+            };
+            match person.access {
                 Access::User => {
-                    return returnable_value(
-                        "Only the administrator can assign a secret Santa",
-                        json,
-                        403,
+                    let text = "Only the administrator can assign a secret Santa";
+                    state.audit.record(
+                        Some(&data.name),
+                        Action::SetSantas,
+                        &data.group_name,
+                        i.1.created_at,
+                        None,
+                        Err(text),
                     );
+                    return returnable_value(&req, text, 403);
                 }
                 Access::Admin => {
-                    if i.1.people.len() < 2 {
-                        return returnable_value("Not enough group members", json, 405);
-                    }
-                    let last_index = i.1.people.len() - 1;
-                    for j in 0..last_index {
-                        i.1.people[j].santa_to = i.1.people[j + 1].name.clone();
+                    let origin = DrawOrigin::Admin {
+                        name: data.name.clone(),
+                    };
+                    created_at = i.1.created_at;
+                    match i.1.draw(origin, Utc::now(), &state.vault) {
+                        Ok(step) => step,
+                        Err(text) => {
+                            state.audit.record(
+                                Some(&data.name),
+                                Action::SetSantas,
+                                &data.group_name,
+                                created_at,
+                                None,
+                                Err(text),
+                            );
+                            return returnable_value(&req, text, 405);
+                        }
                     }
-                    i.1.people[last_index].santa_to = i.1.people[0].name.clone();
-                    i.1.closed = true;
                 }
-            };
+            }
         }
-    }
+    };
 
-    returnable_value("Secret santas are appointed", json, 200)
+    match step {
+        DrawStep::Committed(commitment) => {
+            let saved = persist(state, &mut guard, &data.group_name, Event::DrawCommitted);
+            state.audit.record_saved(
+                Some(&data.name),
+                Action::CommitDraw,
+                &data.group_name,
+                created_at,
+                None,
+                &saved,
+            );
+            if saved.is_err() {
+                return not_saved(&req);
+            }
+            state.publish(
+                &guard,
+                GroupEvent::DrawCommitted {
+                    group_name: data.group_name,
+                    commitment: commitment.clone(),
+                },
+            );
+            returnable_value(
+                &req,
+                &format!(
+                    "Draw commitment published: {commitment}. Members can see it with /groups/verify_draw. Call /groups/set_santas again to run the draw"
+                ),
+                200,
+            )
+        }
+        DrawStep::Drawn(commitment) => {
+            let saved = persist(state, &mut guard, &data.group_name, Event::DrawCompleted);
+            state.audit.record_saved(
+                Some(&data.name),
+                Action::SetSantas,
+                &data.group_name,
+                created_at,
+                None,
+                &saved,
+            );
+            if saved.is_err() {
+                return not_saved(&req);
+            }
+            if let Some(group) = guard.groups.values().find(|g| g.name == data.group_name) {
+                state.mailer.draw(group);
+            }
+            state.publish(
+                &guard,
+                GroupEvent::DrawCompleted {
+                    group_name: data.group_name,
+                    commitment: commitment.clone(),
+                },
+            );
+            returnable_value(
+                &req,
+                &format!("Secret santas are appointed. Draw commitment: {commitment}"),
+                200,
+            )
+        }
+    }
 }
 
-async fn quit_group(mut req: Request<Arc<Mutex<DataBase>>>) -> tide::Result {
-    #[derive(serde::Serialize, serde::Deserialize)]
+async fn quit_group(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
     struct Data {
         name: String,
         group_name: String,
     }
-    let data: Data = req.body_json().await.unwrap_or(Data {
-        name: String::new(),
-        group_name: String::new(),
-    });
-
-    let QueryData { json } = req.query().unwrap_or(QueryData { json: false });
+    let data: Data = read_body(&mut req).await;
 
-    if data.name == "" || data.group_name == "" {
-        return returnable_value("Bad data", json, 400);
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
     }
 
     let state = req.state();
-    let mut guard = state.lock().unwrap();
+    let mut guard = state.database.lock().unwrap();
 
     if !is_person_exist(&guard.groups, &data.name) {
-        return returnable_value("Person does not exist", json, 405);
+        return returnable_value(&req, "Person does not exist", 405);
     }
 
     let mut groups = guard.groups.iter_mut();
 
     match groups.find(|i| i.1.name == data.group_name) {
         None => {
-            return returnable_value("Group with that name does not exist", json, 400);
+            return returnable_value(&req, "Group with that name does not exist", 400);
         }
         Some(i) => {
             if i.1.closed {
-                return returnable_value("Group is closed", json, 400);
+                return returnable_value(&req, "Group is closed", 400);
             }
-            match i
-                .1
-                .people
-                .iter()
-                .find(|j| j.name == data.name)
-                .unwrap()
-                .access
-            {
+            let Some(person) = i.1.people.iter().find(|p| p.name == data.name) else {
+                return returnable_value(&req, "You are not a member of this group", 403);
+            };
+            match person.access {
                 Access::User => {
-                    let index = i.1.people.iter().position(|p| p.name == data.name).unwrap();
-                    i.1.people.remove(index);
+                    i.1.people.retain(|p| p.name != data.name);
                 }
                 Access::Admin => {
                     let count =
                         i.1.people
                             .iter()
-                            .filter(|p| match p.access {
-                                Access::Admin => true,
-                                _ => false,
-                            })
+                            .filter(|p| matches!(p.access, Access::Admin))
                             .count();
                     if count == 1 {
-                        return returnable_value("You can not quit this group", json, 403);
+                        return returnable_value(&req, "You can not quit this group", 403);
                     } else {
-                        let index = i.1.people.iter().position(|p| p.name == data.name).unwrap();
-                        i.1.people.remove(index);
+                        i.1.people.retain(|p| p.name != data.name);
                     }
                 }
             };
         }
     }
 
-    returnable_value("You quit this group", json, 200)
+    if persist(state, &mut guard, &data.group_name, Event::MemberQuit).is_err() {
+        return not_saved(&req);
+    }
+
+    state.publish(
+        &guard,
+        GroupEvent::MemberQuit {
+            group_name: data.group_name,
+            name: data.name,
+        },
+    );
+
+    returnable_value(&req, "You quit this group", 200)
 }
 
-async fn delete_group(mut req: Request<Arc<Mutex<DataBase>>>) -> tide::Result {
-    #[derive(serde::Serialize, serde::Deserialize)]
+async fn delete_group(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
     struct Data {
         name: String,
         group_name: String,
     }
-    let data: Data = req.body_json().await.unwrap_or(Data {
-        name: String::new(),
-        group_name: String::new(),
-    });
+    let data: Data = read_body(&mut req).await;
 
-    let QueryData { json } = req.query().unwrap_or(QueryData { json: false });
-
-    if data.name == "" || data.group_name == "" {
-        return returnable_value("Bad data", json, 400);
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
     }
 
     let state = req.state();
-    let mut guard = state.lock().unwrap();
+    let mut guard = state.database.lock().unwrap();
     let group_id: i8;
 
     if !is_person_exist(&guard.groups, &data.name) {
-        return returnable_value("Person does not exist", json, 405);
+        return returnable_value(&req, "Person does not exist", 405);
     }
 
     match guard.groups.iter().find(|i| i.1.name == data.group_name) {
         None => {
-            return returnable_value("Group with that name does not exist", json, 400);
+            return returnable_value(&req, "Group with that name does not exist", 400);
         }
         Some(i) => {
-            match i
-                .1
-                .people
-                .iter()
-                .find(|j| j.name == data.name)
-                .unwrap()
-                .access
-            {
+            let Some(person) = i.1.people.iter().find(|p| p.name == data.name) else {
+                return returnable_value(&req, "You are not a member of this group", 403);
+            };
+            match person.access {
                 Access::User => {
-                    return returnable_value("You can not delete this group", json, 403);
+                    let text = "You can not delete this group";
+                    state.audit.record(
+                        Some(&data.name),
+                        Action::DeleteGroup,
+                        &data.group_name,
+                        i.1.created_at,
+                        None,
+                        Err(text),
+                    );
+                    return returnable_value(&req, text, 403);
                 }
                 Access::Admin => {
-                    group_id = i.0.clone();
+                    group_id = *i.0;
                 }
             };
         }
     }
 
-    guard.groups.remove(&group_id);
+    // The journal removes the group, take what comes after it first.
+    let created_at = guard.groups[&group_id].created_at;
+    let webhooks = guard.groups[&group_id].webhooks.clone();
+    let saved = persist(state, &mut guard, &data.group_name, Event::GroupDeleted);
+    state.audit.record_saved(
+        Some(&data.name),
+        Action::DeleteGroup,
+        &data.group_name,
+        created_at,
+        None,
+        &saved,
+    );
+    if saved.is_err() {
+        return not_saved(&req);
+    }
+    state.announce(
+        &webhooks,
+        GroupEvent::GroupDeleted {
+            group_name: data.group_name,
+        },
+    );
+
+    returnable_value(&req, "You delete this group", 200)
+}
 
-    returnable_value("You delete this group", json, 200)
+async fn kick(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
+    struct Data {
+        name: String,
+        group_name: String,
+        member: String,
+    }
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() || data.member.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+
+    let state = req.state();
+    let mut guard = state.database.lock().unwrap();
+
+    let Some(group) = guard
+        .groups
+        .values_mut()
+        .find(|g| g.name == data.group_name)
+    else {
+        return returnable_value(&req, "Group with that name does not exist", 400);
+    };
+
+    let created_at = group.created_at;
+    if let Err((text, code)) = group.kick(&data.name, &data.member) {
+        state.audit.record(
+            Some(&data.name),
+            Action::Kick,
+            &data.group_name,
+            created_at,
+            Some(&data.member),
+            Err(text),
+        );
+        return returnable_value(&req, text, code);
+    }
+
+    let saved = persist(state, &mut guard, &data.group_name, Event::MemberKicked);
+    state.audit.record_saved(
+        Some(&data.name),
+        Action::Kick,
+        &data.group_name,
+        created_at,
+        Some(&data.member),
+        &saved,
+    );
+    if saved.is_err() {
+        return not_saved(&req);
+    }
+
+    state.publish(
+        &guard,
+        GroupEvent::MemberKicked {
+            group_name: data.group_name,
+            name: data.member,
+        },
+    );
+
+    returnable_value(&req, "Member removed from the group", 200)
 }
 
-/*
-200 - Ok
-400 - Bad Request
-403 - Forbidden
-405 - Method Not Allowed
-*/
-fn returnable_value(text: &str, is_json: bool, code: usize) -> tide::Result {
-    if is_json {
-        return Ok(json!({
-            "code": code,
-            "message": text
-        })
-        .into());
+async fn reopen(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
+    struct Data {
+        name: String,
+        group_name: String,
     }
-    Ok(text.into())
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+
+    let state = req.state();
+    let mut guard = state.database.lock().unwrap();
+
+    let Some(group) = guard
+        .groups
+        .values_mut()
+        .find(|g| g.name == data.group_name)
+    else {
+        return returnable_value(&req, "Group with that name does not exist", 400);
+    };
+
+    let created_at = group.created_at;
+    if let Err((text, code)) = group.reopen(&data.name, Utc::now()) {
+        state.audit.record(
+            Some(&data.name),
+            Action::Reopen,
+            &data.group_name,
+            created_at,
+            None,
+            Err(text),
+        );
+        return returnable_value(&req, text, code);
+    }
+
+    let saved = persist(state, &mut guard, &data.group_name, Event::GroupReopened);
+    state.audit.record_saved(
+        Some(&data.name),
+        Action::Reopen,
+        &data.group_name,
+        created_at,
+        None,
+        &saved,
+    );
+    if saved.is_err() {
+        return not_saved(&req);
+    }
+
+    state.publish(
+        &guard,
+        GroupEvent::GroupReopened {
+            group_name: data.group_name,
+        },
+    );
+
+    returnable_value(&req, "Group is open again", 200)
+}
+
+/// Journals a change to a group, see `journal`. When that fails the change
+/// is rolled back, `database` is put back the way the snapshot and the
+/// journal have it, and the request must fail, see `not_saved`.
+fn persist(
+    state: &State,
+    database: &mut DataBase,
+    group_name: &str,
+    event: Event,
+) -> std::io::Result<()> {
+    let Err(err) = state.journal.record(database, group_name, event) else {
+        return Ok(());
+    };
+    tracing::error!(error = %err, "Failed to persist the database");
+    match state.journal.durable() {
+        Ok(durable) => *database = durable,
+        Err(err) => tracing::error!(error = %err, "Failed to roll back an unsaved change"),
+    }
+    Err(err)
+}
+
+/// Answer to a request whose change `persist` rolled back.
+fn not_saved<S>(req: &Request<S>) -> tide::Result {
+    returnable_value(req, "The change could not be saved, try again later", 500)
+}
+
+fn returnable_value<S>(req: &Request<S>, text: &str, code: u16) -> tide::Result {
+    reply(req, code, &Message(text))
 }
 
-fn is_person_exist(groups: &HashMap<i8, Group>, name: &String) -> bool {
+/// Reads the request body as a JSON document or, for HTML forms, as
+/// url-encoded fields. A missing or malformed body gives the default value.
+///
+/// JSON is tried first whatever the `Content-Type` says, because `curl -d`
+/// labels JSON bodies as form data.
+async fn read_body<T, S>(req: &mut Request<S>) -> T
+where
+    T: serde::de::DeserializeOwned + Default,
+{
+    let is_form = req
+        .content_type()
+        .is_some_and(|mime| mime.essence() == "application/x-www-form-urlencoded");
+    let body = req.body_bytes().await.unwrap_or_default();
+
+    match serde_json::from_slice(&body) {
+        Ok(data) => data,
+        Err(_) if is_form => serde_urlencoded::from_bytes(&body).unwrap_or_default(),
+        Err(_) => T::default(),
+    }
+}
+
+fn is_person_exist(groups: &HashMap<i8, Group>, name: &str) -> bool {
     groups
         .iter()
         .any(|i| i.1.people.iter().any(|j| j.name.eq(name)))
 }
 
-async fn join_group(mut req: Request<Arc<Mutex<DataBase>>>) -> tide::Result {
-    #[derive(serde::Serialize, serde::Deserialize)]
+/// Whether `name` may be a member name. Control characters are refused,
+/// which keeps the vault labels that start with a NUL byte, like the draw
+/// seed's and the shipping addresses', apart from the assignment labels,
+/// which are giver names.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(char::is_control)
+}
+
+async fn join_group(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
     struct Data {
         name: String,
         group_name: String,
+        #[serde(default)]
+        email: String,
     }
-    let data: Data = req.body_json().await.unwrap_or(Data {
-        name: String::new(),
-        group_name: String::new(),
-    });
+    let data: Data = read_body(&mut req).await;
 
-    let QueryData { json } = req.query().unwrap_or(QueryData { json: false });
-
-    if data.name == "" || data.group_name == "" {
-        return returnable_value("Bad data", json, 400);
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+    if !is_valid_name(&data.name) {
+        return returnable_value(&req, "Names can't contain control characters", 400);
+    }
+    if !data.email.is_empty() && !notify::is_valid_email(&data.email) {
+        return returnable_value(&req, "Bad email", 400);
     }
 
     let state = req.state();
-    let mut guard = state.lock().unwrap();
+    let mut guard = state.database.lock().unwrap();
 
     if is_person_exist(&guard.groups, &data.name) {
-        return returnable_value("You have to leave from group to join other", json, 405);
+        return returnable_value(&req, "You have to leave from group to join other", 405);
     }
+    let token;
 
     let mut groups = guard.groups.iter_mut();
 
     match groups.find(|i| i.1.name == data.group_name) {
         None => {
-            return returnable_value("Group with that name does not exist", json, 400);
+            return returnable_value(&req, "Group with that name does not exist", 400);
         }
         Some(i) => {
             if i.1.closed {
-                return returnable_value("This group is closed!", json, 403);
+                return returnable_value(&req, "This group is closed!", 403);
             }
-            let new_person = Person {
-                name: data.name,
+            let mut new_person = Person {
+                name: data.name.clone(),
                 santa_to: String::new(),
+                email: Some(data.email.clone()).filter(|email| !email.is_empty()),
+                gift_bought: false,
                 access: Access::User,
+                exclusions: Vec::new(),
+                display_name: None,
+                contact: Contact::default(),
+                privacy: Privacy::default(),
+                address: None,
+                token_hash: None,
             };
+            token = new_person.issue_token();
             i.1.people.push(new_person);
         }
     }
 
-    returnable_value(
-        format!("Done! You are in group \"{}\" now", data.group_name).as_str(),
-        json,
+    if persist(state, &mut guard, &data.group_name, Event::MemberJoined).is_err() {
+        return not_saved(&req);
+    }
+
+    state.publish(
+        &guard,
+        GroupEvent::MemberJoined {
+            group_name: data.group_name.clone(),
+            name: data.name.clone(),
+        },
+    );
+
+    reply(
+        &req,
         200,
+        &views::Welcome {
+            text: &format!("Done! You are in group \"{}\" now", data.group_name),
+            name: &data.name,
+            token: &token,
+        },
     )
 }
 
-async fn create_group(mut req: Request<Arc<Mutex<DataBase>>>) -> tide::Result {
-    #[derive(serde::Serialize, serde::Deserialize)]
+async fn create_group(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
     struct Data {
         name: String,
         group_name: String,
+        #[serde(default)]
+        email: String,
     }
-    let data: Data = req.body_json().await.unwrap_or(Data {
-        name: String::new(),
-        group_name: String::new(),
-    });
-
-    let QueryData { json } = req.query().unwrap_or(QueryData { json: false });
+    let data: Data = read_body(&mut req).await;
 
-    if data.name == "" || data.group_name == "" {
-        return returnable_value("Bad data", json, 400);
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+    if !is_valid_name(&data.name) {
+        return returnable_value(&req, "Names can't contain control characters", 400);
+    }
+    if !data.email.is_empty() && !notify::is_valid_email(&data.email) {
+        return returnable_value(&req, "Bad email", 400);
     }
 
     let state = req.state();
-    let mut guard = state.lock().unwrap();
+    let mut guard = state.database.lock().unwrap();
     let mut groups = guard.groups.iter();
 
     if is_person_exist(&guard.groups, &data.name) {
-        return returnable_value("You have to leave from group to create other", json, 405);
+        return returnable_value(&req, "You have to leave from group to create other", 405);
     }
+    let token;
 
     match groups.find(|i| i.1.name == data.group_name) {
         None => {
-            let new_group_id: i8 = guard.groups.len() as i8;
-            let new_admin = Person {
-                name: data.name,
+            // Ids of deleted groups are free again. The journal refers to
+            // groups by id, so an id in use must never be handed out.
+            let Some(new_group_id) = (0..=i8::MAX).find(|id| !guard.groups.contains_key(id)) else {
+                return returnable_value(&req, "There are too many groups", 400);
+            };
+            let mut new_admin = Person {
+                name: data.name.clone(),
                 santa_to: String::new(),
+                email: Some(data.email.clone()).filter(|email| !email.is_empty()),
+                gift_bought: false,
                 access: Access::Admin,
+                exclusions: Vec::new(),
+                display_name: None,
+                contact: Contact::default(),
+                privacy: Privacy::default(),
+                address: None,
+                token_hash: None,
             };
+            token = new_admin.issue_token();
             let new_group = Group {
-                name: data.group_name,
+                name: data.group_name.clone(),
                 people: vec![new_admin],
                 closed: false,
+                webhooks: Vec::new(),
+                draw_date: None,
+                exchange_date: None,
+                draw_all_day: false,
+                exchange_all_day: false,
+                reminders_sent: Vec::new(),
+                auto_draw_at: None,
+                drawn_by: None,
+                drawn_at: None,
+                draw_proof: None,
+                draw_history: Vec::new(),
+                created_at: Some(Utc::now()),
             };
             guard.groups.insert(new_group_id, new_group);
         }
         Some(_) => {
-            return returnable_value("Group with this name is exist", json, 400);
+            return returnable_value(&req, "Group with this name is exist", 400);
         }
     }
 
-    returnable_value("Group is created", json, 200)
+    if persist(state, &mut guard, &data.group_name, Event::GroupCreated).is_err() {
+        return not_saved(&req);
+    }
+
+    reply(
+        &req,
+        200,
+        &views::Welcome {
+            text: "Group is created",
+            name: &data.name,
+            token: &token,
+        },
+    )
 }
 
-async fn get_members(mut req: Request<Arc<Mutex<DataBase>>>) -> tide::Result {
-    #[derive(serde::Serialize, serde::Deserialize)]
+async fn get_members(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
     struct Data {
         name: String,
         group_name: String,
+        #[serde(default)]
+        token: String,
     }
-    let data: Data = req.body_json().await.unwrap_or(Data {
-        name: String::new(),
-        group_name: String::new(),
-    });
+    let data: Data = read_body(&mut req).await;
 
-    let QueryData { json } = req.query().unwrap_or(QueryData { json: false });
-
-    if data.name == "" || data.group_name == "" {
-        return returnable_value("Bad data", json, 400);
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
     }
 
     let state = req.state();
-    let guard = state.lock().unwrap();
+    let guard = state.database.lock().unwrap();
     let mut groups = guard.groups.iter();
-    let mut out_message: String = String::new();
 
     match groups.find(|i| i.1.name == data.group_name) {
-        Some(g) => {
-            if json {
-                return Ok(json!({
-                    "code": 200,
-                    "message": {
-                        "group_name": data.group_name,
-                        "people": g.1.people
-                    }
-                })
-                .into());
-            } else {
-                let mut id: i8 = 0;
-                for person in &g.1.people {
-                    out_message += format!(
-                        "{}. Name: {}. Access: {:?}\n",
-                        id,
-                        person.name.as_str(),
-                        person.access
-                    )
-                    .as_str();
-                    id += 1;
-                }
-            }
-        }
-        None => {
-            return returnable_value("There is no group with that name", json, 400);
+        Some(g)
+            if g.1
+                .people
+                .iter()
+                .any(|p| p.name == data.name && p.has_token(&data.token)) =>
+        {
+            reply(
+                &req,
+                200,
+                &views::Members {
+                    group_name: &data.group_name,
+                    people: &g.1.people,
+                    viewer: &data.name,
+                },
+            )
         }
+        Some(g) => reply(&req, 200, &views::GroupSummary { group: g.1 }),
+        None => returnable_value(&req, "There is no group with that name", 400),
     }
-
-    Ok(out_message.into())
 }
 
-async fn get_groups(req: Request<Arc<Mutex<DataBase>>>) -> tide::Result {
+async fn get_groups(req: Request<State>) -> tide::Result {
     let state = req.state();
+    let guard = state.database.lock().unwrap();
 
-    let QueryData { json } = req.query().unwrap_or(QueryData { json: false });
-
-    let guard = state.lock().unwrap();
-    let groups = guard.groups.iter();
-    let mut out_message: String = String::new();
-
-    if guard.groups.len() == 0 {
-        return returnable_value("There is no any group", json, 200);
-    } else {
-        if json {
-            out_message = serde_json::to_string(state).unwrap();
-        } else {
-            out_message += "Groups: \n";
-            for (id, group) in groups {
-                out_message += format!(
-                    "Id: {}. Group name: \"{}\". Persons: {}. Is closed: {}\n",
-                    id,
-                    group.name,
-                    group.people.len(),
-                    group.closed
-                )
-                .as_str();
-            }
-        }
+    if guard.groups.is_empty() {
+        return returnable_value(&req, "There is no any group", 200);
     }
 
-    Ok(out_message.into())
+    reply(&req, 200, &views::Groups { database: &guard })
 }
 
-async fn set_new_admin(mut req: Request<Arc<Mutex<DataBase>>>) -> tide::Result {
-    #[derive(serde::Serialize, serde::Deserialize)]
+async fn set_new_admin(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
     struct Data {
         name: String,
         group_name: String,
         name_new_admin: String,
     }
-    let data: Data = req.body_json().await.unwrap_or(Data {
-        name: String::new(),
-        group_name: String::new(),
-        name_new_admin: String::new(),
-    });
+    let data: Data = read_body(&mut req).await;
 
-    let QueryData { json } = req.query().unwrap_or(QueryData { json: false });
-
-    if data.name == "" || data.group_name == "" || data.name_new_admin == "" {
-        return returnable_value("Bad data", json, 400);
+    if data.name.is_empty() || data.group_name.is_empty() || data.name_new_admin.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
     }
 
     let state = req.state();
-    let mut guard = state.lock().unwrap();
+    let mut guard = state.database.lock().unwrap();
 
     if !is_person_exist(&guard.groups, &data.name)
         || !is_person_exist(&guard.groups, &data.name_new_admin)
     {
-        return returnable_value("There is no such person", json, 405);
+        return returnable_value(&req, "There is no such person", 405);
     }
 
+    let created_at;
     let mut groups = guard.groups.iter_mut();
 
-    match groups.find(|i| (i.1.name == data.name)) {
+    match groups.find(|i| i.1.name == data.group_name) {
         None => {
-            return returnable_value("There is no group with that name", json, 400);
+            return returnable_value(&req, "There is no group with that name", 400);
         }
         Some(g) => {
-            match g
-                .1
-                .people
-                .iter()
-                .find(|i| (i.name == data.name))
-                .unwrap()
-                .access
-            {
+            let Some(person) = g.1.people.iter().find(|p| p.name == data.name) else {
+                return returnable_value(&req, "You are not a member of this group", 403);
+            };
+            match person.access {
                 Access::User => {
-                    return returnable_value("No rights", json, 400);
+                    let text = "No rights";
+                    state.audit.record(
+                        Some(&data.name),
+                        Action::SetNewAdmin,
+                        &data.group_name,
+                        g.1.created_at,
+                        Some(&data.name_new_admin),
+                        Err(text),
+                    );
+                    return returnable_value(&req, text, 400);
                 }
                 Access::Admin => {
-                    g.1.people
-                        .iter_mut()
-                        .find(|i| (i.name == data.name_new_admin))
-                        .unwrap()
-                        .access = Access::Admin;
+                    let Some(new_admin) =
+                        g.1.people
+                            .iter_mut()
+                            .find(|i| i.name == data.name_new_admin)
+                    else {
+                        let text = "The new administrator is not a member of this group";
+                        state.audit.record(
+                            Some(&data.name),
+                            Action::SetNewAdmin,
+                            &data.group_name,
+                            g.1.created_at,
+                            Some(&data.name_new_admin),
+                            Err(text),
+                        );
+                        return returnable_value(&req, text, 400);
+                    };
+                    new_admin.access = Access::Admin;
+                    created_at = g.1.created_at;
                 }
             }
         }
     }
 
-    returnable_value("Admin installed", json, 200)
+    let saved = persist(state, &mut guard, &data.group_name, Event::AdminChanged);
+    state.audit.record_saved(
+        Some(&data.name),
+        Action::SetNewAdmin,
+        &data.group_name,
+        created_at,
+        Some(&data.name_new_admin),
+        &saved,
+    );
+    if saved.is_err() {
+        return not_saved(&req);
+    }
+
+    state.publish(
+        &guard,
+        GroupEvent::AdminChanged {
+            group_name: data.group_name,
+            name: data.name_new_admin,
+            admin: true,
+        },
+    );
+
+    returnable_value(&req, "Admin installed", 200)
 }
 
-async fn quit_admin(mut req: Request<Arc<Mutex<DataBase>>>) -> tide::Result {
-    #[derive(serde::Serialize, serde::Deserialize)]
+async fn quit_admin(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
     struct Data {
         name: String,
         group_name: String,
     }
-    let data: Data = req.body_json().await.unwrap_or(Data {
-        name: String::new(),
-        group_name: String::new(),
-    });
-
-    let QueryData { json } = req.query().unwrap_or(QueryData { json: false });
+    let data: Data = read_body(&mut req).await;
 
-    if data.name == "" || data.group_name == "" {
-        return returnable_value("Bad data", json, 400);
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
     }
 
     let state = req.state();
-    let mut guard = state.lock().unwrap();
+    let mut guard = state.database.lock().unwrap();
 
     if !is_person_exist(&guard.groups, &data.name) {
-        return returnable_value("Person does not exist", json, 405);
+        return returnable_value(&req, "Person does not exist", 405);
     }
 
+    let created_at;
     let mut groups = guard.groups.iter_mut();
 
     match groups.find(|i| i.1.name == data.group_name) {
         None => {
-            return returnable_value("Group with that name does not exist", json, 400);
+            return returnable_value(&req, "Group with that name does not exist", 400);
         }
         Some(i) => {
-            match i
-                .1
-                .people
-                .iter()
-                .find(|j| j.name == data.name)
-                .unwrap()
-                .access
-            {
+            let Some(person) = i.1.people.iter().find(|p| p.name == data.name) else {
+                return returnable_value(&req, "You are not a member of this group", 403);
+            };
+            match person.access {
                 Access::User => {
-                    return returnable_value("You are not an admin!", json, 403);
+                    let text = "You are not an admin!";
+                    state.audit.record(
+                        Some(&data.name),
+                        Action::QuitAdmin,
+                        &data.group_name,
+                        i.1.created_at,
+                        None,
+                        Err(text),
+                    );
+                    return returnable_value(&req, text, 403);
                 }
                 Access::Admin => {
                     let count =
                         i.1.people
                             .iter()
-                            .filter(|p| match p.access {
-                                Access::Admin => true,
-                                _ => false,
-                            })
+                            .filter(|p| matches!(p.access, Access::Admin))
                             .count();
                     if count == 1 {
-                        return returnable_value(
-                            "You cannot remove your administrator rights!",
-                            json,
-                            403,
+                        let text = "You cannot remove your administrator rights!";
+                        state.audit.record(
+                            Some(&data.name),
+                            Action::QuitAdmin,
+                            &data.group_name,
+                            i.1.created_at,
+                            None,
+                            Err(text),
                         );
+                        return returnable_value(&req, text, 403);
                     } else {
-                        i.1.people
-                            .iter_mut()
-                            .find(|j| j.name == data.name)
-                            .unwrap()
-                            .access = Access::User;
+                        for person in i.1.people.iter_mut().filter(|j| j.name == data.name) {
+                            person.access = Access::User;
+                        }
+                        created_at = i.1.created_at;
                     }
                 }
             };
         }
     }
 
-    returnable_value("You have removed your administrator rights!", json, 200)
+    let saved = persist(state, &mut guard, &data.group_name, Event::AdminChanged);
+    state.audit.record_saved(
+        Some(&data.name),
+        Action::QuitAdmin,
+        &data.group_name,
+        created_at,
+        None,
+        &saved,
+    );
+    if saved.is_err() {
+        return not_saved(&req);
+    }
+
+    state.publish(
+        &guard,
+        GroupEvent::AdminChanged {
+            group_name: data.group_name,
+            name: data.name,
+            admin: false,
+        },
+    );
+
+    returnable_value(&req, "You have removed your administrator rights!", 200)
 }
 
-async fn index(mut req: Request<Arc<Mutex<DataBase>>>) -> tide::Result {
-    #[derive(serde::Serialize, serde::Deserialize)]
+async fn invite(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
+    struct Data {
+        name: String,
+        group_name: String,
+        email: String,
+    }
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() || !notify::is_valid_email(&data.email) {
+        return returnable_value(&req, "Bad data", 400);
+    }
+
+    let state = req.state();
+    let guard = state.database.lock().unwrap();
+
+    match guard.groups.values().find(|g| g.name == data.group_name) {
+        None => returnable_value(&req, "Group with that name does not exist", 400),
+        Some(group) => {
+            if !group.people.iter().any(|p| p.name == data.name) {
+                return returnable_value(&req, "You are not a member of this group", 403);
+            }
+            if group.closed {
+                return returnable_value(&req, "This group is closed!", 403);
+            }
+            state
+                .mailer
+                .invitation(&data.name, &data.group_name, &data.email);
+            returnable_value(&req, "Invitation sent", 200)
+        }
+    }
+}
+
+async fn remind(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
+    struct Data {
+        name: String,
+        group_name: String,
+        #[serde(default)]
+        text: String,
+    }
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+
+    let state = req.state();
+    let guard = state.database.lock().unwrap();
+
+    match guard.groups.values().find(|g| g.name == data.group_name) {
+        None => returnable_value(&req, "Group with that name does not exist", 400),
+        Some(group) => {
+            match group.people.iter().find(|p| p.name == data.name) {
+                Some(Person {
+                    access: Access::Admin,
+                    ..
+                }) => {}
+                _ => {
+                    return returnable_value(&req, "Only the administrator can send reminders", 403)
+                }
+            }
+            let note = if !data.text.is_empty() {
+                data.text.clone()
+            } else if group.closed {
+                format!(
+                    "The gift exchange in group \"{}\" is coming, don't forget your gift!",
+                    group.name
+                )
+            } else {
+                format!("The draw in group \"{}\" has not happened yet.", group.name)
+            };
+            for person in &group.people {
+                state.mailer.reminder(group, person, &note);
+            }
+            returnable_value(&req, "Reminders sent", 200)
+        }
+    }
+}
+
+async fn set_dates(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
+    struct Data {
+        name: String,
+        group_name: String,
+        #[serde(default)]
+        draw_date: String,
+        #[serde(default)]
+        exchange_date: String,
+        #[serde(default)]
+        auto_draw_at: String,
+    }
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+    let (Ok(draw_date), Ok(exchange_date), Ok(auto_draw_at)) = (
+        parse_date(&data.draw_date),
+        parse_date(&data.exchange_date),
+        parse_date(&data.auto_draw_at),
+    ) else {
+        return returnable_value(
+            &req,
+            "Dates must look like 2024-12-24 or 2024-12-24T18:00:00Z, or none",
+            400,
+        );
+    };
+
+    let state = req.state();
+    let mut guard = state.database.lock().unwrap();
+
+    match guard
+        .groups
+        .values_mut()
+        .find(|g| g.name == data.group_name)
+    {
+        None => {
+            return returnable_value(&req, "Group with that name does not exist", 400);
+        }
+        Some(group) => {
+            match group.people.iter().find(|p| p.name == data.name) {
+                Some(Person {
+                    access: Access::Admin,
+                    ..
+                }) => {}
+                _ => return returnable_value(&req, "Only the administrator can set dates", 403),
+            }
+            if matches!(auto_draw_at, Some(Some(_))) && group.closed {
+                return returnable_value(&req, "Group is closed", 400);
+            }
+            let draw_date = draw_date.unwrap_or(group.draw_date);
+            let exchange_date = exchange_date.unwrap_or(group.exchange_date);
+            if let (Some(draw), Some(exchange)) = (draw_date, exchange_date) {
+                if draw > exchange {
+                    return returnable_value(&req, "The draw must happen before the exchange", 400);
+                }
+            }
+            // Reminders for a moved date have to go out again.
+            if draw_date != group.draw_date {
+                group.reminders_sent.retain(|key| !key.starts_with("draw_"));
+            }
+            if exchange_date != group.exchange_date {
+                group
+                    .reminders_sent
+                    .retain(|key| !key.starts_with("exchange_"));
+            }
+            group.draw_date = draw_date;
+            group.exchange_date = exchange_date;
+            if !data.draw_date.is_empty() {
+                group.draw_all_day = is_plain_date(&data.draw_date);
+            }
+            if !data.exchange_date.is_empty() {
+                group.exchange_all_day = is_plain_date(&data.exchange_date);
+            }
+            group.auto_draw_at = auto_draw_at.unwrap_or(group.auto_draw_at);
+        }
+    }
+
+    if persist(state, &mut guard, &data.group_name, Event::DatesSet).is_err() {
+        return not_saved(&req);
+    }
+
+    returnable_value(&req, "Dates are set", 200)
+}
+
+/// Parses a change to a date: an RFC 3339 timestamp or a plain date, which
+/// means midnight UTC, sets it, `none` clears it and an empty string leaves
+/// it as it is.
+fn parse_date(text: &str) -> Result<Option<Option<DateTime<Utc>>>, chrono::ParseError> {
+    if text.is_empty() {
+        return Ok(None);
+    }
+    if text == "none" {
+        return Ok(Some(None));
+    }
+    match DateTime::parse_from_rfc3339(text) {
+        Ok(date) => Ok(Some(Some(date.with_timezone(&Utc)))),
+        Err(_) => {
+            let date = NaiveDate::parse_from_str(text, "%Y-%m-%d")?;
+            Ok(Some(Some(date.and_hms_opt(0, 0, 0).unwrap().and_utc())))
+        }
+    }
+}
+
+/// Whether `parse_date` read `text` as a plain date.
+fn is_plain_date(text: &str) -> bool {
+    DateTime::parse_from_rfc3339(text).is_err()
+        && NaiveDate::parse_from_str(text, "%Y-%m-%d").is_ok()
+}
+
+async fn gift_bought(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
+    struct Data {
+        name: String,
+        group_name: String,
+    }
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+
+    let state = req.state();
+    let mut guard = state.database.lock().unwrap();
+
+    match guard
+        .groups
+        .values_mut()
+        .find(|g| g.name == data.group_name)
+    {
+        None => {
+            return returnable_value(&req, "Group with that name does not exist", 400);
+        }
+        Some(group) => {
+            if !group.closed {
+                return returnable_value(&req, "Given group is not closed", 400);
+            }
+            match group.people.iter_mut().find(|p| p.name == data.name) {
+                None => {
+                    return returnable_value(&req, "There is no such person in given group", 400);
+                }
+                Some(person) => person.gift_bought = true,
+            }
+        }
+    }
+
+    if persist(state, &mut guard, &data.group_name, Event::GiftBought).is_err() {
+        return not_saved(&req);
+    }
+
+    returnable_value(&req, "Your gift is marked as bought", 200)
+}
+
+async fn index(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
     struct User {
         name: String,
     }
-    let user: User = req.body_json().await.unwrap_or(User {
-        name: String::new(),
-    });
+    let user: User = read_body(&mut req).await;
+
+    if user.name.is_empty() {
+        return returnable_value(&req, "Who are you?", 200);
+    }
+
+    returnable_value(&req, format!("Hello {}!", user.name).as_str(), 200)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::testing::TestApp;
+    use crate::Group;
+
+    /// Runs `read` on the group with that name.
+    fn with_group<T>(app: &TestApp, group_name: &str, read: impl FnOnce(&mut Group) -> T) -> T {
+        let mut guard = app.state.database.lock().unwrap();
+        let group = guard
+            .groups
+            .values_mut()
+            .find(|g| g.name == group_name)
+            .unwrap();
+        read(group)
+    }
 
-    let QueryData { json } = req.query().unwrap_or(QueryData { json: false });
+    #[async_std::test]
+    async fn dates_of_a_closed_group_stay_as_they_are_on_error() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        let body = json!({
+            "name": "alice",
+            "group_name": "party",
+            "exchange_date": "2030-12-24",
+        });
+        assert_eq!(app.post("/groups/set_dates", body).await.0, 200);
+        app.draw("party", "alice").await;
+        with_group(&app, "party", |group| {
+            group.reminders_sent.push("exchange_1d".to_string())
+        });
+
+        let (code, _) = app
+            .post(
+                "/groups/set_dates",
+                json!({
+                    "name": "alice",
+                    "group_name": "party",
+                    "exchange_date": "2030-12-25",
+                    "auto_draw_at": "2030-12-01",
+                }),
+            )
+            .await;
+        assert_eq!(code, 400);
+        with_group(&app, "party", |group| {
+            assert_eq!(group.reminders_sent, ["exchange_1d"]);
+            assert_eq!(
+                group.exchange_date.unwrap().to_rfc3339(),
+                "2030-12-24T00:00:00+00:00"
+            );
+        });
+    }
 
-    if user.name == "" {
-        return returnable_value("Who are you?", json, 200);
+    #[async_std::test]
+    async fn scheduled_draw_can_be_cancelled() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        let set = |auto_draw_at: &str| json!({ "name": "alice", "group_name": "party", "auto_draw_at": auto_draw_at });
+
+        assert_eq!(
+            app.post("/groups/set_dates", set("2030-12-01")).await.0,
+            200
+        );
+        assert!(with_group(&app, "party", |group| group
+            .auto_draw_at
+            .is_some()));
+        // Leaving it out keeps it.
+        assert_eq!(app.post("/groups/set_dates", set("")).await.0, 200);
+        assert!(with_group(&app, "party", |group| group
+            .auto_draw_at
+            .is_some()));
+        assert_eq!(app.post("/groups/set_dates", set("none")).await.0, 200);
+        assert!(with_group(&app, "party", |group| group
+            .auto_draw_at
+            .is_none()));
     }
 
-    returnable_value(format!("Hello {}!", user.name).as_str(), json, 200)
+    #[async_std::test]
+    async fn new_admin_is_looked_up_by_group_name() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob", "carol"]).await;
+
+        let (code, _) = app
+            .post(
+                "/groups/new_admin",
+                json!({ "name": "alice", "group_name": "party", "name_new_admin": "bob" }),
+            )
+            .await;
+        assert_eq!(code, 200);
+
+        // Only an admin may remove members.
+        let (code, _) = app
+            .post(
+                "/groups/kick",
+                json!({ "name": "bob", "group_name": "party", "member": "carol" }),
+            )
+            .await;
+        assert_eq!(code, 200);
+    }
+
+    #[async_std::test]
+    async fn acting_on_a_group_you_are_not_in_is_refused() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        app.group("other", &["carol", "dave"]).await;
+
+        for path in [
+            "/groups/set_santas",
+            "/groups/quit",
+            "/groups/delete",
+            "/groups/quit_admin",
+        ] {
+            let body = json!({ "name": "alice", "group_name": "other" });
+            let (code, message) = app.post(path, body).await;
+            assert_eq!(code, 403, "{path}: {message}");
+        }
+        let body = json!({ "name": "alice", "group_name": "other", "name_new_admin": "dave" });
+        assert_eq!(app.post("/groups/new_admin", body).await.0, 403);
+        let body = json!({ "name": "carol", "group_name": "other", "name_new_admin": "bob" });
+        assert_eq!(app.post("/groups/new_admin", body).await.0, 400);
+
+        assert!(!app.state.database.is_poisoned());
+        with_group(&app, "other", |group| {
+            assert_eq!(group.people.len(), 2);
+            assert!(!group.is_admin("bob"));
+        });
+    }
+
+    #[async_std::test]
+    async fn names_with_control_characters_are_refused() {
+        let app = TestApp::new();
+        app.group("party", &["alice"]).await;
+
+        for name in ["\0seed", "\0address\0alice", "bob\n"] {
+            let body = json!({ "name": name, "group_name": "party" });
+            assert_eq!(app.post("/groups/join", body).await.0, 400, "{name:?}");
+            let body = json!({ "name": name, "group_name": "other" });
+            assert_eq!(app.post("/groups/create", body).await.0, 400, "{name:?}");
+        }
+
+        assert_eq!(app.state.database.lock().unwrap().groups.len(), 1);
+        with_group(&app, "party", |group| assert_eq!(group.people.len(), 1));
+    }
 }
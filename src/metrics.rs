@@ -0,0 +1,300 @@
+//! Prometheus metrics, served at `/metrics` in the text exposition format.
+//!
+//! Counters live in one process-wide registry, so code without access to
+//! `State`, like `storage`, can record too. Group and member counts are read
+//! from the database on every scrape.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::State;
+
+/// Upper bounds of histogram buckets, in seconds.
+const BUCKETS: [f64; 10] = [0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        Histogram {
+            counts: [0; BUCKETS.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: Duration) {
+        let value = value.as_secs_f64();
+        for (bound, count) in BUCKETS.iter().zip(&mut self.counts) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (bound, count) in BUCKETS.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {}",
+            self.count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{labels} {}", self.count);
+    }
+}
+
+struct Registry {
+    /// Keyed by route, method and status.
+    requests: BTreeMap<(String, String, u16), u64>,
+    request_seconds: BTreeMap<String, Histogram>,
+    /// Keyed by who ran the draw.
+    draws: BTreeMap<&'static str, u64>,
+    /// Keyed by why the draw was impossible.
+    failed_draws: BTreeMap<&'static str, u64>,
+    persist_seconds: Histogram,
+    persist_failures: u64,
+    /// Keyed by route class, see `rate_limit`.
+    rate_limited: BTreeMap<&'static str, u64>,
+}
+
+impl Registry {
+    const fn new() -> Registry {
+        Registry {
+            requests: BTreeMap::new(),
+            request_seconds: BTreeMap::new(),
+            draws: BTreeMap::new(),
+            failed_draws: BTreeMap::new(),
+            persist_seconds: Histogram::new(),
+            persist_failures: 0,
+            rate_limited: BTreeMap::new(),
+        }
+    }
+}
+
+pub fn draw_completed(origin: &'static str) {
+    *REGISTRY.lock().unwrap().draws.entry(origin).or_default() += 1;
+}
+
+pub fn draw_failed(reason: &'static str) {
+    *REGISTRY
+        .lock()
+        .unwrap()
+        .failed_draws
+        .entry(reason)
+        .or_default() += 1;
+}
+
+pub fn persisted(duration: Duration, ok: bool) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.persist_seconds.observe(duration);
+    if !ok {
+        registry.persist_failures += 1;
+    }
+}
+
+pub fn rate_limited(class: &'static str) {
+    *REGISTRY
+        .lock()
+        .unwrap()
+        .rate_limited
+        .entry(class)
+        .or_default() += 1;
+}
+
+/// Counts requests and their latency per route.
+///
+/// It runs after every other middleware, so requests answered before
+/// routing, like rate limited ones and CORS preflights, are never counted
+/// per path. Those are in `rate_limited` or not counted at all.
+pub struct RequestMetrics;
+
+#[tide::utils::async_trait]
+impl Middleware<State> for RequestMetrics {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        let path = req.url().path().to_string();
+
+        let res = next.run(req).await;
+
+        let status = u16::from(res.status());
+        // Paths nothing is routed to would make a new series each. Handlers
+        // don't answer 404 or 405, only the router does.
+        let route = if status == 404 || status == 405 {
+            "unmatched".to_string()
+        } else {
+            path
+        };
+        let mut registry = REGISTRY.lock().unwrap();
+        *registry
+            .requests
+            .entry((route.clone(), method, status))
+            .or_default() += 1;
+        registry
+            .request_seconds
+            .entry(route)
+            .or_insert_with(Histogram::new)
+            .observe(start.elapsed());
+        Ok(res)
+    }
+}
+
+pub async fn endpoint(req: Request<State>) -> tide::Result {
+    let mut out = String::new();
+
+    {
+        let guard = req.state().database.lock().unwrap();
+        let closed = guard.groups.values().filter(|g| g.closed).count();
+        let members: usize = guard.groups.values().map(|g| g.people.len()).sum();
+
+        out += "# HELP secret_santa_groups Groups by whether the draw is done.\n";
+        out += "# TYPE secret_santa_groups gauge\n";
+        let _ = writeln!(
+            out,
+            "secret_santa_groups{{closed=\"false\"}} {}",
+            guard.groups.len() - closed
+        );
+        let _ = writeln!(out, "secret_santa_groups{{closed=\"true\"}} {closed}");
+        out += "# HELP secret_santa_members Group memberships.\n";
+        out += "# TYPE secret_santa_members gauge\n";
+        let _ = writeln!(out, "secret_santa_members {members}");
+    }
+
+    let registry = REGISTRY.lock().unwrap();
+
+    out += "# HELP secret_santa_http_requests_total HTTP requests by route, method and status.\n";
+    out += "# TYPE secret_santa_http_requests_total counter\n";
+    for ((route, method, status), count) in &registry.requests {
+        let _ = writeln!(
+            out,
+            "secret_santa_http_requests_total{{route=\"{}\",method=\"{method}\",status=\"{status}\"}} {count}",
+            escape_label(route)
+        );
+    }
+    out += "# HELP secret_santa_http_request_duration_seconds HTTP request latency by route.\n";
+    out += "# TYPE secret_santa_http_request_duration_seconds histogram\n";
+    for (route, histogram) in &registry.request_seconds {
+        histogram.write(
+            &mut out,
+            "secret_santa_http_request_duration_seconds",
+            &format!("route=\"{}\"", escape_label(route)),
+        );
+    }
+
+    out += "# HELP secret_santa_draws_total Draws performed, by who ran them.\n";
+    out += "# TYPE secret_santa_draws_total counter\n";
+    for (origin, count) in &registry.draws {
+        let _ = writeln!(
+            out,
+            "secret_santa_draws_total{{origin=\"{origin}\"}} {count}"
+        );
+    }
+    out += "# HELP secret_santa_draw_failures_total Draws that were impossible, by reason.\n";
+    out += "# TYPE secret_santa_draw_failures_total counter\n";
+    for (reason, count) in &registry.failed_draws {
+        let _ = writeln!(
+            out,
+            "secret_santa_draw_failures_total{{reason=\"{}\"}} {count}",
+            escape_label(reason)
+        );
+    }
+
+    out += "# HELP secret_santa_persist_duration_seconds Time spent writing the database file.\n";
+    out += "# TYPE secret_santa_persist_duration_seconds histogram\n";
+    registry
+        .persist_seconds
+        .write(&mut out, "secret_santa_persist_duration_seconds", "");
+    out += "# HELP secret_santa_persist_failures_total Failed writes of the database file.\n";
+    out += "# TYPE secret_santa_persist_failures_total counter\n";
+    let _ = writeln!(
+        out,
+        "secret_santa_persist_failures_total {}",
+        registry.persist_failures
+    );
+
+    out += "# HELP secret_santa_rate_limited_total Requests refused by the rate limiter, by route class.\n";
+    out += "# TYPE secret_santa_rate_limited_total counter\n";
+    for (class, count) in &registry.rate_limited {
+        let _ = writeln!(
+            out,
+            "secret_santa_rate_limited_total{{class=\"{class}\"}} {count}"
+        );
+    }
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(out);
+    res.set_content_type("text/plain; version=0.0.4; charset=utf-8");
+    Ok(res)
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{Method, Request};
+
+    use crate::config::{Limit, RateLimitConfig};
+    use crate::testing::{url, TestApp};
+
+    #[async_std::test]
+    async fn unrouted_paths_are_not_labels() {
+        let app = TestApp::with_config(|config| {
+            config.rate_limit = RateLimitConfig {
+                write: Limit {
+                    burst: 5,
+                    per_minute: 1,
+                },
+                ..RateLimitConfig::default()
+            };
+            config.cors.allowed_origins = vec!["https://santa.example".to_string()];
+        });
+        // The registry is shared by every test in the process.
+        let prefix = format!("/nothing-{:016x}", rand::random::<u64>());
+
+        for i in 0..20 {
+            let mut req = Request::new(Method::Post, url(&format!("{prefix}/{i}")));
+            req.set_peer_addr(Some("203.0.113.7:1234"));
+            app.respond(req).await;
+        }
+        let mut req = Request::new(Method::Options, url(&format!("{prefix}/preflight")));
+        req.insert_header("Origin", "https://santa.example");
+        req.insert_header("Access-Control-Request-Method", "POST");
+        app.respond(req).await;
+
+        let scrape = app.get("/metrics").await.body_string().await.unwrap();
+        assert!(!scrape.contains(&prefix), "{scrape}");
+        assert!(scrape.contains("route=\"unmatched\""), "{scrape}");
+        assert!(
+            scrape.contains("secret_santa_rate_limited_total{class=\"write\"}"),
+            "{scrape}"
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Versions of the `data.base` format and the steps between them.
+//!
+//! The file is an envelope `{"version": N, "data": {...}}`, where `data` is
+//! the serialized `DataBase`. Files from before versioning are the bare
+//! `DataBase` and count as version 0.
+//!
+//! A change to `Person` or `Group` that `#[serde(default)]` can't cover gets
+//! a new step at the end of `MIGRATIONS` and bumps `CURRENT_VERSION`.
+
+use serde_json::{json, Value};
+
+pub const CURRENT_VERSION: u64 = MIGRATIONS.len() as u64;
+
+struct Migration {
+    description: &'static str,
+    /// Rewrites the whole file, envelope included, from the version at the
+    /// step's index to the next one.
+    apply: fn(&mut Value) -> Result<(), String>,
+}
+
+const MIGRATIONS: [Migration; 1] = [Migration {
+    description: "wrap the database in a versioned envelope",
+    apply: wrap_in_envelope,
+}];
+
+fn wrap_in_envelope(file: &mut Value) -> Result<(), String> {
+    if !file.get("groups").is_some_and(Value::is_object) {
+        return Err("expected an object with \"groups\"".to_string());
+    }
+    *file = json!({ "version": 1, "data": file.take() });
+    Ok(())
+}
+
+/// The format version of a parsed file.
+pub fn version(file: &Value) -> u64 {
+    file.get("version").and_then(Value::as_u64).unwrap_or(0)
+}
+
+/// Brings a file up to `CURRENT_VERSION` and returns its `data`.
+pub fn migrate(mut file: Value) -> Result<Value, String> {
+    let from = version(&file);
+    if from > CURRENT_VERSION {
+        return Err(format!(
+            "the file has version {from}, this build only knows up to {CURRENT_VERSION}"
+        ));
+    }
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        tracing::info!(
+            from = version,
+            to = version + 1,
+            step = migration.description,
+            "Migrating database file"
+        );
+        (migration.apply)(&mut file)
+            .map_err(|err| format!("migration to version {} failed: {err}", version + 1))?;
+        file["version"] = json!(version + 1);
+    }
+
+    match file {
+        Value::Object(mut envelope) => envelope
+            .remove("data")
+            .ok_or_else(|| "the envelope has no data".to_string()),
+        _ => Err("the file is not a JSON object".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{migrate, version, CURRENT_VERSION};
+
+    #[test]
+    fn bare_databases_are_version_0() {
+        let bare = json!({ "groups": { "1": { "name": "party" } } });
+        assert_eq!(version(&bare), 0);
+        assert_eq!(migrate(bare.clone()).unwrap(), bare);
+
+        let current = json!({ "version": CURRENT_VERSION, "data": bare.clone() });
+        assert_eq!(version(&current), CURRENT_VERSION);
+        assert_eq!(migrate(current).unwrap(), bare);
+    }
+
+    #[test]
+    fn unknown_files_are_refused() {
+        assert!(migrate(json!({ "people": [] })).is_err());
+        assert!(migrate(json!([])).is_err());
+        let newer = json!({ "version": CURRENT_VERSION + 1, "data": { "groups": {} } });
+        assert!(migrate(newer).is_err());
+    }
+}
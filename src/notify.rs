@@ -0,0 +1,567 @@
+//! Email notifications. `Mailer` composes the messages and hands them to a
+//! `Notifier`, which is either an SMTP relay or a file/log sink, depending on
+//! the `mail` section of the config.
+//!
+//! Sending happens in background tasks, so a slow mail server never holds up
+//! a request. Failures are only reported to stderr.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use async_std::{
+    io::{prelude::BufReadExt, BufReader, WriteExt},
+    net::TcpStream,
+    task,
+};
+use base64::Engine;
+use futures::future::BoxFuture;
+
+use crate::config::{Config, MailConfig};
+use crate::vault::Vault;
+use crate::{Group, Person};
+
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    /// The recipient's assignment, when the body tells it on a line of its
+    /// own, see `assignment_line`.
+    pub assignment: Option<String>,
+    /// The recipient's member token, see `token_line` and `auth`.
+    pub token: Option<String>,
+}
+
+fn assignment_line(santa_to: &str) -> String {
+    format!("You secret santa to - {santa_to}")
+}
+
+fn token_line(token: &str) -> String {
+    format!("Your token: {token}")
+}
+
+pub trait Notifier: Send + Sync {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// Writes every email to a file, or to stderr when there is no path. Those
+/// are read by whoever runs the server, so assignments and tokens are
+/// redacted.
+pub struct LogNotifier {
+    path: Option<PathBuf>,
+}
+
+impl Notifier for LogNotifier {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut body = match &mail.assignment {
+                Some(santa_to) => mail
+                    .body
+                    .replace(&assignment_line(santa_to), &assignment_line("[redacted]")),
+                None => mail.body.clone(),
+            };
+            if let Some(token) = &mail.token {
+                body = body.replace(&token_line(token), &token_line("[redacted]"));
+            }
+            let text = format!(
+                "To: {}\nSubject: {}\n\n{}\n----\n",
+                mail.to, mail.subject, body
+            );
+            match &self.path {
+                Some(path) => OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?
+                    .write_all(text.as_bytes()),
+                None => io::stderr().write_all(text.as_bytes()),
+            }
+        })
+    }
+}
+
+/// Plain SMTP client for a local or trusted relay. No TLS or authentication.
+pub struct SmtpNotifier {
+    host: String,
+    port: u16,
+    from: String,
+}
+
+impl Notifier for SmtpNotifier {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+            let mut reader = BufReader::new(stream.clone());
+            let mut writer = stream;
+
+            expect_reply(&mut reader, 220).await?;
+            command(&mut writer, &mut reader, "EHLO secret-santa-service", 250).await?;
+            command(
+                &mut writer,
+                &mut reader,
+                &format!("MAIL FROM:<{}>", self.from),
+                250,
+            )
+            .await?;
+            command(
+                &mut writer,
+                &mut reader,
+                &format!("RCPT TO:<{}>", mail.to),
+                250,
+            )
+            .await?;
+            command(&mut writer, &mut reader, "DATA", 354).await?;
+
+            let mut message = format!(
+                "From: {}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\n\
+                 Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+                self.from,
+                mail.to,
+                encode_header(&mail.subject)
+            );
+            for line in mail.body.lines() {
+                // Dot-stuffing, so a line with a single dot can't end the message.
+                if line.starts_with('.') {
+                    message.push('.');
+                }
+                message += line;
+                message += "\r\n";
+            }
+            message += ".";
+            command(&mut writer, &mut reader, &message, 250).await?;
+            command(&mut writer, &mut reader, "QUIT", 221).await?;
+            Ok(())
+        })
+    }
+}
+
+async fn command(
+    writer: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    line: &str,
+    code: u16,
+) -> io::Result<()> {
+    writer.write_all(format!("{line}\r\n").as_bytes()).await?;
+    expect_reply(reader, code).await
+}
+
+/// Reads a possibly multi-line SMTP reply and checks its status code.
+async fn expect_reply(reader: &mut BufReader<TcpStream>, code: u16) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "SMTP server closed the connection",
+            ));
+        }
+        let status: u16 = line.get(..3).and_then(|s| s.parse().ok()).unwrap_or(0);
+        if status != code {
+            return Err(io::Error::other(format!(
+                "Unexpected SMTP reply: {}",
+                line.trim_end()
+            )));
+        }
+        // "250-..." continues the reply, "250 ..." ends it.
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+/// Encodes a header value as an RFC 2047 encoded word when it isn't plain
+/// ASCII, which also keeps line breaks out of the header.
+fn encode_header(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        value.to_string()
+    } else {
+        format!(
+            "=?utf-8?B?{}?=",
+            base64::engine::general_purpose::STANDARD.encode(value)
+        )
+    }
+}
+
+pub fn is_valid_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.is_empty()
+                && !email
+                    .chars()
+                    .any(|c| c.is_whitespace() || c.is_control() || "<>,;".contains(c))
+        }
+        None => false,
+    }
+}
+
+#[derive(Clone)]
+pub struct Mailer {
+    notifier: Option<Arc<dyn Notifier>>,
+    public_url: String,
+    vault: Vault,
+}
+
+impl Mailer {
+    pub fn from_config(config: &Config, vault: Vault) -> Mailer {
+        let notifier: Option<Arc<dyn Notifier>> = match &config.mail {
+            MailConfig::Disabled => None,
+            MailConfig::Log { path } => Some(Arc::new(LogNotifier { path: path.clone() })),
+            MailConfig::Smtp { host, port, from } => Some(Arc::new(SmtpNotifier {
+                host: host.clone(),
+                port: *port,
+                from: from.clone(),
+            })),
+        };
+        Mailer {
+            notifier,
+            public_url: config.public_url.clone(),
+            vault,
+        }
+    }
+
+    fn deliver(&self, mail: Mail) {
+        let Some(notifier) = self.notifier.clone() else {
+            return;
+        };
+        task::spawn(async move {
+            if let Err(err) = notifier.send(&mail).await {
+                tracing::warn!(to = %mail.to, error = %err, "Failed to send email");
+            }
+        });
+    }
+
+    /// Tells every member with an email whom they drew. Each email only
+    /// contains the recipient's own assignment.
+    pub fn draw(&self, group: &Group) {
+        for person in &group.people {
+            let Some(email) = person.notification_email() else {
+                continue;
+            };
+            let Some(santa_to) = self.vault.open(&group.name, &person.name, &person.santa_to)
+            else {
+                continue;
+            };
+            self.deliver(Mail {
+                to: email.to_string(),
+                subject: format!("Secret Santa: the draw in \"{}\" is done", group.name),
+                body: format!(
+                    "Hello {}!\n\nThe draw in group \"{}\" is done.\n{}\n\nKeep it secret!\n{}/ui",
+                    person.label(),
+                    group.name,
+                    assignment_line(&santa_to),
+                    self.public_url
+                ),
+                assignment: Some(santa_to),
+                token: None,
+            });
+        }
+    }
+
+    pub fn invitation(&self, from: &str, group_name: &str, email: &str) {
+        self.deliver(Mail {
+            to: email.to_string(),
+            subject: format!("Secret Santa: {from} invites you to \"{group_name}\""),
+            body: format!(
+                "Hello!\n\n{} invites you to the secret santa group \"{}\".\nJoin it at {}/ui\n",
+                from, group_name, self.public_url
+            ),
+            assignment: None,
+            token: None,
+        });
+    }
+
+    /// Sends a member their token, see `auth`. Goes out even when they
+    /// don't want notifications, there is no other way to get it.
+    pub fn token(&self, group_name: &str, person: &Person, token: &str) {
+        let Some(email) = &person.email else {
+            return;
+        };
+        self.deliver(Mail {
+            to: email.clone(),
+            subject: format!("Secret Santa: your token for \"{group_name}\""),
+            body: format!(
+                "Hello {}!\n\n{}\nYou need it to see whom you gift in group \"{}\". Keep it secret!\n{}/ui\n",
+                person.label(),
+                token_line(token),
+                group_name,
+                self.public_url
+            ),
+            assignment: None,
+            token: Some(token.to_string()),
+        });
+    }
+
+    /// Sends `note` to one member. After the draw the reminder repeats whom
+    /// they gift, since only they receive it.
+    pub fn reminder(&self, group: &Group, person: &Person, note: &str) {
+        let Some(email) = person.notification_email() else {
+            return;
+        };
+        let mut body = format!("Hello {}!\n\n{}\n", person.label(), note);
+        let assignment = self
+            .vault
+            .open(&group.name, &person.name, &person.santa_to)
+            .filter(|_| group.closed);
+        if let Some(santa_to) = &assignment {
+            body += &format!("{}\n", assignment_line(santa_to));
+        }
+        body += &format!("\n{}/ui\n", self.public_url);
+
+        self.deliver(Mail {
+            to: email.to_string(),
+            subject: format!("Secret Santa reminder for \"{}\"", group.name),
+            body,
+            assignment,
+            token: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use base64::Engine;
+    use futures::future::BoxFuture;
+    use serde_json::json;
+
+    use super::{
+        assignment_line, encode_header, LogNotifier, Mail, Mailer, Notifier, SmtpNotifier,
+    };
+    use crate::testing::TestApp;
+
+    /// Serves one SMTP session on an ephemeral port, answering RCPT TO with
+    /// `rcpt_reply`. Returns the port and the lines the client sent.
+    fn smtp_server(rcpt_reply: u16) -> (u16, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let session = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut lines = Vec::new();
+            let mut in_data = false;
+            stream.write_all(b"220 fake ESMTP\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.strip_suffix("\r\n").unwrap().to_string();
+                lines.push(line.clone());
+                let reply = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    "250 queued".to_string()
+                } else if line.starts_with("EHLO ") {
+                    "250-fake\r\n250 8BITMIME".to_string()
+                } else if line.starts_with("MAIL FROM:") {
+                    "250 ok".to_string()
+                } else if line.starts_with("RCPT TO:") {
+                    format!("{rcpt_reply} rcpt")
+                } else if line == "DATA" {
+                    in_data = true;
+                    "354 go ahead".to_string()
+                } else if line == "QUIT" {
+                    stream.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    "500 what".to_string()
+                };
+                stream.write_all(format!("{reply}\r\n").as_bytes()).unwrap();
+            }
+            lines
+        });
+        (port, session)
+    }
+
+    fn notifier(port: u16) -> SmtpNotifier {
+        SmtpNotifier {
+            host: "127.0.0.1".to_string(),
+            port,
+            from: "santa@example.com".to_string(),
+        }
+    }
+
+    #[async_std::test]
+    async fn smtp_exchange() {
+        let (port, session) = smtp_server(250);
+        let mail = Mail {
+            to: "bob@example.com".to_string(),
+            subject: "Hi".to_string(),
+            body: "First\n.\n..two\nLast".to_string(),
+            assignment: None,
+            token: None,
+        };
+        notifier(port).send(&mail).await.unwrap();
+
+        let lines = session.join().unwrap();
+        assert_eq!(
+            lines[..4],
+            [
+                "EHLO secret-santa-service",
+                "MAIL FROM:<santa@example.com>",
+                "RCPT TO:<bob@example.com>",
+                "DATA",
+            ]
+        );
+        let data = &lines[4..lines.len() - 1];
+        let body_start = data.iter().position(String::is_empty).unwrap();
+        let headers = &data[..body_start];
+        assert!(headers.contains(&"From: santa@example.com".to_string()));
+        assert!(headers.contains(&"To: bob@example.com".to_string()));
+        assert!(headers.contains(&"Subject: Hi".to_string()));
+        // Lines starting with a dot get another one, a lone dot ends it.
+        assert_eq!(
+            data[body_start + 1..],
+            ["First", "..", "...two", "Last", "."]
+        );
+        assert_eq!(lines.last().unwrap(), "QUIT");
+    }
+
+    #[async_std::test]
+    async fn smtp_rejection_is_an_error() {
+        let (port, session) = smtp_server(550);
+        let mail = Mail {
+            to: "nobody@example.com".to_string(),
+            subject: "Hi".to_string(),
+            body: "Hello".to_string(),
+            assignment: None,
+            token: None,
+        };
+        let err = notifier(port).send(&mail).await.unwrap_err();
+        assert!(err.to_string().contains("550"), "{err}");
+        let lines = session.join().unwrap();
+        assert!(!lines.contains(&"DATA".to_string()));
+    }
+
+    #[async_std::test]
+    async fn logged_mail_leaves_out_the_assignment() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mail.log");
+        let notifier = LogNotifier {
+            path: Some(path.clone()),
+        };
+        let mail = Mail {
+            to: "bob@example.com".to_string(),
+            subject: "Draw".to_string(),
+            body: format!("Hello Bob!\n{}\nBye", assignment_line("Carol")),
+            assignment: Some("Carol".to_string()),
+            token: None,
+        };
+        notifier.send(&mail).await.unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(log.contains("To: bob@example.com"), "{log}");
+        assert!(log.contains(&assignment_line("[redacted]")), "{log}");
+        assert!(!log.contains("Carol"), "{log}");
+    }
+
+    #[test]
+    fn headers_are_encoded_when_not_plain_ascii() {
+        assert_eq!(encode_header("Secret Santa"), "Secret Santa");
+
+        let decode = |encoded: &str| {
+            let base64 = encoded
+                .strip_prefix("=?utf-8?B?")
+                .and_then(|rest| rest.strip_suffix("?="))
+                .unwrap();
+            let bytes = base64::engine::general_purpose::STANDARD
+                .decode(base64)
+                .unwrap();
+            String::from_utf8(bytes).unwrap()
+        };
+        for value in ["Тайный Санта", "Hi\r\nBcc: eve@example.com", "tab\there"] {
+            let encoded = encode_header(value);
+            assert!(encoded.is_ascii() && !encoded.contains(['\r', '\n']));
+            assert_eq!(decode(&encoded), value);
+        }
+    }
+
+    /// Keeps every mail instead of sending it.
+    #[derive(Default)]
+    struct Outbox(Mutex<Vec<(String, String)>>);
+
+    impl Notifier for Outbox {
+        fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, std::io::Result<()>> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((mail.to.clone(), mail.body.clone()));
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[async_std::test]
+    async fn draw_mail_only_has_the_recipients_assignment() {
+        let names = ["alice", "bob", "carol", "dave"];
+        let app = TestApp::new();
+        app.group("party", &names).await;
+        for name in names {
+            let body = json!({
+                "name": name,
+                "group_name": "party",
+                "email": format!("{name}@example.com"),
+            });
+            assert_eq!(app.post("/groups/profile", body).await.0, 200);
+        }
+        app.draw("party", "alice").await;
+
+        let outbox = Arc::new(Outbox::default());
+        let mailer = Mailer {
+            notifier: Some(outbox.clone()),
+            public_url: "http://santa.test".to_string(),
+            vault: app.state.vault.clone(),
+        };
+        mailer.draw(
+            app.state
+                .database
+                .lock()
+                .unwrap()
+                .groups
+                .values()
+                .next()
+                .unwrap(),
+        );
+
+        // Mails go out in background tasks.
+        for _ in 0..500 {
+            if outbox.0.lock().unwrap().len() == names.len() {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mails = outbox.0.lock().unwrap();
+        let database = app.state.database.lock().unwrap();
+        let group = database.groups.values().next().unwrap();
+        assert_eq!(mails.len(), names.len());
+        for person in &group.people {
+            let (_, body) = mails
+                .iter()
+                .find(|(to, _)| *to == format!("{}@example.com", person.name))
+                .unwrap();
+            let giftee = app
+                .state
+                .vault
+                .open("party", &person.name, &person.santa_to)
+                .unwrap();
+            assert!(body.contains(&format!("{}\n", assignment_line(&giftee))));
+            for other in names {
+                if other != person.name && other != giftee {
+                    assert!(!body.contains(other), "{} sees {other}", person.name);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Optional member details: a display name, how to reach them, and which
+//! of those other members of the group get to see.
+//!
+//! The display name is always shown. Email and the contact note are private
+//! unless the member makes them visible, only the member sees their own.
+//! The shipping address is sealed by `Vault` and only revealed to the
+//! member's santa by `/to-who-gift` once the group is closed.
+
+use tide::Request;
+
+use crate::journal::Event;
+use crate::vault::Vault;
+use crate::{not_saved, notify, persist, read_body, returnable_value, Person, State};
+
+const MAX_DISPLAY_NAME: usize = 64;
+const MAX_CONTACT_NOTE: usize = 200;
+const MAX_ADDRESS: usize = 500;
+
+/// Vault label a member's address is sealed under, see `is_valid_name`.
+fn address_label(name: &str) -> String {
+    format!("\0address\0{name}")
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Contact {
+    /// Free-form way to reach the member, like a chat handle.
+    pub note: Option<String>,
+    /// Draw results and reminders are sent to the member's email.
+    pub by_email: bool,
+}
+
+impl Default for Contact {
+    fn default() -> Contact {
+        Contact {
+            note: None,
+            by_email: true,
+        }
+    }
+}
+
+/// What other members of the group see besides the name and display name.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Privacy {
+    pub show_email: bool,
+    pub show_contact: bool,
+}
+
+impl Person {
+    /// The display name, or the name when there is none.
+    pub fn label(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+
+    /// The email, if `viewer` may see it.
+    pub fn email_for(&self, viewer: &str) -> Option<&str> {
+        self.email
+            .as_deref()
+            .filter(|_| self.privacy.show_email || self.name == viewer)
+    }
+
+    /// The contact note, if `viewer` may see it.
+    pub fn contact_for(&self, viewer: &str) -> Option<&str> {
+        self.contact
+            .note
+            .as_deref()
+            .filter(|_| self.privacy.show_contact || self.name == viewer)
+    }
+
+    /// The shipping address, for the member's santa only.
+    pub fn address(&self, group_name: &str, vault: &Vault) -> Option<String> {
+        let sealed = self.address.as_deref()?;
+        vault.open(group_name, &address_label(&self.name), sealed)
+    }
+
+    /// Where to send notifications, unless the member opted out.
+    pub fn notification_email(&self) -> Option<&str> {
+        self.email.as_deref().filter(|_| self.contact.by_email)
+    }
+}
+
+/// Updates the caller's own details, given their token. Fields left out
+/// stay as they are, an empty text clears one.
+pub async fn update(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
+    struct Data {
+        name: String,
+        group_name: String,
+        display_name: Option<String>,
+        email: Option<String>,
+        contact: Option<String>,
+        address: Option<String>,
+        by_email: Option<bool>,
+        show_email: Option<bool>,
+        show_contact: Option<bool>,
+        #[serde(default)]
+        token: String,
+    }
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+    let clean = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(|value| Some(value.trim().to_string()).filter(|value| !value.is_empty()))
+    };
+    let display_name = clean(&data.display_name);
+    let email = clean(&data.email);
+    let contact = clean(&data.contact);
+    let address = clean(&data.address);
+    if display_name
+        .iter()
+        .flatten()
+        .any(|value| value.chars().count() > MAX_DISPLAY_NAME)
+    {
+        return returnable_value(&req, "Display name is too long", 400);
+    }
+    if contact
+        .iter()
+        .flatten()
+        .any(|value| value.chars().count() > MAX_CONTACT_NOTE)
+    {
+        return returnable_value(&req, "Contact note is too long", 400);
+    }
+    if address
+        .iter()
+        .flatten()
+        .any(|value| value.chars().count() > MAX_ADDRESS)
+    {
+        return returnable_value(&req, "Address is too long", 400);
+    }
+    if email
+        .iter()
+        .flatten()
+        .any(|value| !notify::is_valid_email(value))
+    {
+        return returnable_value(&req, "Bad email", 400);
+    }
+
+    let state = req.state();
+    let mut guard = state.database.lock().unwrap();
+
+    let Some(group) = guard
+        .groups
+        .values_mut()
+        .find(|g| g.name == data.group_name)
+    else {
+        return returnable_value(&req, "Group with that name does not exist", 400);
+    };
+    let Some(person) = group.people.iter_mut().find(|p| p.name == data.name) else {
+        return returnable_value(&req, "You are not a member of this group", 403);
+    };
+    if !person.has_token(&data.token) {
+        return returnable_value(&req, "Wrong or missing token", 403);
+    }
+
+    if let Some(display_name) = display_name {
+        person.display_name = display_name;
+    }
+    if let Some(email) = email {
+        person.email = email;
+    }
+    if let Some(contact) = contact {
+        person.contact.note = contact;
+    }
+    if let Some(address) = address {
+        person.address = address.map(|address| {
+            state
+                .vault
+                .seal(&data.group_name, &address_label(&data.name), &address)
+        });
+    }
+    if let Some(by_email) = data.by_email {
+        person.contact.by_email = by_email;
+    }
+    if let Some(show_email) = data.show_email {
+        person.privacy.show_email = show_email;
+    }
+    if let Some(show_contact) = data.show_contact {
+        person.privacy.show_contact = show_contact;
+    }
+
+    if persist(state, &mut guard, &data.group_name, Event::ProfileChanged).is_err() {
+        return not_saved(&req);
+    }
+
+    returnable_value(&req, "Your details are saved", 200)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tide::http::{Method, Request};
+
+    use crate::testing::{url, TestApp};
+
+    const FORMATS: [&str; 4] = ["text/plain", "application/json", "text/html", "text/csv"];
+
+    async fn update(app: &TestApp, name: &str, details: serde_json::Value) {
+        let mut body = json!({ "name": name, "group_name": "party" });
+        body.as_object_mut()
+            .unwrap()
+            .extend(details.as_object().unwrap().clone());
+        let (code, message) = app.post("/groups/profile", body).await;
+        assert_eq!(code, 200, "{message}");
+    }
+
+    /// Bob as the member list shows him to `viewer`.
+    async fn bob_seen_by(app: &TestApp, viewer: &str) -> serde_json::Value {
+        let body = json!({ "name": viewer, "group_name": "party" });
+        let (_, message) = app.post("/groups/members", body).await;
+        message["people"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|person| person["name"] == "bob")
+            .unwrap()
+            .clone()
+    }
+
+    #[async_std::test]
+    async fn private_details_are_shown_only_when_the_member_allows_it() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        let details = json!({ "email": "bob@example.com", "contact": "@bob" });
+        update(&app, "bob", details).await;
+
+        let bob = bob_seen_by(&app, "alice").await;
+        assert!(bob["email"].is_null() && bob["contact"].is_null(), "{bob}");
+        for format in FORMATS {
+            let body = json!({ "name": "alice", "group_name": "party" });
+            let answer = app.post_accepting("/groups/members", body, format).await;
+            assert!(!answer.contains("bob@example.com"), "{format}: {answer}");
+            assert!(!answer.contains("@bob"), "{format}: {answer}");
+        }
+        let bob = bob_seen_by(&app, "bob").await;
+        assert_eq!(bob["email"], "bob@example.com");
+        assert_eq!(bob["contact"], "@bob");
+
+        update(&app, "bob", json!({ "show_email": true })).await;
+        let bob = bob_seen_by(&app, "alice").await;
+        assert_eq!(bob["email"], "bob@example.com");
+        assert!(bob["contact"].is_null(), "{bob}");
+
+        update(
+            &app,
+            "bob",
+            json!({ "show_email": false, "show_contact": true }),
+        )
+        .await;
+        let bob = bob_seen_by(&app, "alice").await;
+        assert!(bob["email"].is_null(), "{bob}");
+        assert_eq!(bob["contact"], "@bob");
+    }
+
+    #[async_std::test]
+    async fn left_out_fields_stay_and_empty_ones_are_cleared() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        let details = json!({
+            "display_name": "Bobby",
+            "email": "bob@example.com",
+            "contact": "@bob",
+        });
+        update(&app, "bob", details).await;
+
+        update(&app, "bob", json!({ "display_name": " Bob " })).await;
+        let bob = bob_seen_by(&app, "bob").await;
+        assert_eq!(bob["display_name"], "Bob");
+        assert_eq!(bob["email"], "bob@example.com");
+        assert_eq!(bob["contact"], "@bob");
+
+        update(&app, "bob", json!({ "by_email": false })).await;
+        {
+            let guard = app.state.database.lock().unwrap();
+            let bob = &guard.groups.values().next().unwrap().people[1];
+            assert_eq!(bob.email.as_deref(), Some("bob@example.com"));
+            assert_eq!(bob.notification_email(), None);
+        }
+
+        update(&app, "bob", json!({ "email": "", "contact": "  " })).await;
+        let bob = bob_seen_by(&app, "bob").await;
+        assert_eq!(bob["display_name"], "Bob");
+        assert!(bob["email"].is_null() && bob["contact"].is_null(), "{bob}");
+
+        update(&app, "bob", json!({ "display_name": "" })).await;
+        let bob = bob_seen_by(&app, "bob").await;
+        assert!(bob["display_name"].is_null(), "{bob}");
+
+        let body = json!({ "name": "bob", "group_name": "party", "email": "not an email" });
+        assert_eq!(app.post("/groups/profile", body).await.0, 400);
+    }
+
+    #[async_std::test]
+    async fn only_the_santa_sees_the_address_once_the_group_is_closed() {
+        let names = ["alice", "bob", "carol"];
+        let app = TestApp::new();
+        app.group("party", &names).await;
+        let body = json!({ "name": "bob", "group_name": "party", "address": "1 Elm Street" });
+        assert_eq!(app.post("/groups/profile", body).await.0, 200);
+
+        for name in names {
+            let body = json!({ "name": name, "group_name": "party" });
+            let (code, message) = app.post("/to-who-gift", body).await;
+            assert_eq!(code, 400, "{message}");
+        }
+        app.draw("party", "alice").await;
+
+        for name in names {
+            let body = json!({ "name": name, "group_name": "party" });
+            let (code, message) = app.post("/to-who-gift", body.clone()).await;
+            assert_eq!(code, 200, "{message}");
+            if message["gifted"] == "bob" {
+                assert_eq!(message["address"], "1 Elm Street");
+            } else {
+                assert!(message["address"].is_null(), "{name}: {message}");
+            }
+
+            for format in FORMATS {
+                for path in ["/groups/members", "/groups/export"] {
+                    let mut body = body.clone();
+                    body["assignments"] = json!(true);
+                    let answer = app.post_accepting(path, body, format).await;
+                    assert!(!answer.contains("Elm"), "{path} {format}: {answer}");
+                }
+            }
+        }
+        for format in FORMATS {
+            let mut req = Request::new(Method::Get, url("/groups/list"));
+            req.insert_header("Accept", format);
+            let answer = app.respond(req).await.body_string().await.unwrap();
+            assert!(!answer.contains("Elm"), "{format}: {answer}");
+        }
+
+        let journal = std::fs::read_to_string(app.dir().join("data.journal")).unwrap();
+        assert!(!journal.contains("Elm"));
+    }
+}
@@ -0,0 +1,257 @@
+//! Token bucket rate limiting per client IP.
+//!
+//! Every request takes a token from the bucket of its IP. There are no
+//! buckets per user: names aren't authenticated, so anyone could drain the
+//! bucket of someone else by sending their name. Buckets refill
+//! continuously. Limits differ by route class, see `RateLimitConfig`.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tide::{http::Method, Middleware, Next, Request, StatusCode};
+
+use crate::config::{Limit, RateLimitConfig};
+use crate::metrics;
+use crate::render::{reply_with_status, Message};
+use crate::State;
+
+/// Buckets kept at most. At the limit, full buckets are dropped, as they
+/// behave the same as missing ones, and then the least recently used until
+/// half are left, so the sweep runs once per many new clients.
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Class {
+    Join,
+    Write,
+    Read,
+}
+
+impl Class {
+    fn name(self) -> &'static str {
+        match self {
+            Class::Join => "join",
+            Class::Write => "write",
+            Class::Read => "read",
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refilled(&self, limit: Limit, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * per_second(limit)).min(f64::from(limit.burst))
+    }
+
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        self.tokens = self.refilled(limit, now);
+        self.updated = now;
+    }
+}
+
+fn per_second(limit: Limit) -> f64 {
+    f64::from(limit.per_minute) / 60.0
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    /// Keyed by route class and client IP.
+    buckets: Mutex<HashMap<(Class, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config,
+            buckets: Mutex::default(),
+        }
+    }
+
+    fn limit(&self, class: Class) -> Limit {
+        match class {
+            Class::Join => self.config.join,
+            Class::Write => self.config.write,
+            Class::Read => self.config.read,
+        }
+    }
+
+    /// Takes a token from the bucket of `ip`, or returns how long to wait
+    /// until it has one.
+    fn take(&self, class: Class, ip: String, now: Instant) -> Result<(), Duration> {
+        let limit = self.limit(class);
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let key = (class, ip);
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            self.evict(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        });
+        bucket.refill(limit, now);
+        if bucket.tokens < 1.0 {
+            let rate = per_second(limit);
+            // Without refill the bucket never recovers, a minute is as good
+            // an answer as any.
+            let seconds = if rate > 0.0 {
+                (1.0 - bucket.tokens) / rate
+            } else {
+                60.0
+            };
+            return Err(Duration::from_secs_f64(seconds));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Brings the buckets down to half of `MAX_BUCKETS`.
+    fn evict(&self, buckets: &mut HashMap<(Class, String), Bucket>, now: Instant) {
+        buckets.retain(|(class, _), bucket| {
+            let limit = self.limit(*class);
+            bucket.refilled(limit, now) < f64::from(limit.burst)
+        });
+
+        let keep = MAX_BUCKETS / 2;
+        if buckets.len() > keep {
+            let mut by_age: Vec<_> = buckets
+                .iter()
+                .map(|(key, bucket)| (bucket.updated, key.clone()))
+                .collect();
+            let excess = by_age.len() - keep;
+            by_age.select_nth_unstable_by_key(excess, |(updated, _)| *updated);
+            for (_, key) in &by_age[..excess] {
+                buckets.remove(key);
+            }
+        }
+    }
+}
+
+fn classify(method: Method, path: &str) -> Option<Class> {
+    match path {
+        // Probes and scrapes come from infrastructure on a fixed schedule.
+        "/healthz" | "/readyz" | "/metrics" => None,
+        "/groups/join" | "/groups/invite" | "/ui/login" => Some(Class::Join),
+        _ if method == Method::Post => Some(Class::Write),
+        _ => Some(Class::Read),
+    }
+}
+
+#[tide::utils::async_trait]
+impl Middleware<State> for RateLimiter {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let Some(class) = classify(req.method(), req.url().path()) else {
+            return Ok(next.run(req).await);
+        };
+
+        let ip = if self.config.trust_proxy {
+            req.remote()
+        } else {
+            req.peer_addr()
+        };
+        let Some(ip) = ip.map(strip_port) else {
+            return Ok(next.run(req).await);
+        };
+
+        match self.take(class, ip, Instant::now()) {
+            Ok(()) => Ok(next.run(req).await),
+            Err(wait) => {
+                metrics::rate_limited(class.name());
+                let mut res = reply_with_status(
+                    &req,
+                    StatusCode::TooManyRequests,
+                    &Message("Too many requests, slow down"),
+                )?;
+                res.insert_header("Retry-After", wait.as_secs_f64().ceil().to_string());
+                Ok(res)
+            }
+        }
+    }
+}
+
+/// Turns `1.2.3.4:5678` or `[::1]:5678` into the bare address. Forwarded
+/// addresses may come without a port and are kept as they are.
+fn strip_port(addr: &str) -> String {
+    addr.parse::<SocketAddr>()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|_| addr.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use serde_json::json;
+    use tide::http::{Method, Request};
+    use tide::StatusCode;
+
+    use super::{Class, RateLimiter, MAX_BUCKETS};
+    use crate::config::{Limit, RateLimitConfig};
+    use crate::testing::{url, TestApp};
+
+    fn strict() -> RateLimitConfig {
+        RateLimitConfig {
+            write: Limit {
+                burst: 2,
+                per_minute: 1,
+            },
+            ..RateLimitConfig::default()
+        }
+    }
+
+    async fn post_from(app: &TestApp, ip: &str, name: &str) -> u64 {
+        let mut req = Request::new(Method::Post, url("/"));
+        req.set_peer_addr(Some(format!("{ip}:1234")));
+        req.insert_header("Accept", "application/json");
+        req.set_body(json!({ "name": name }));
+        let mut res = app.respond(req).await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        let code = body["code"].as_u64().unwrap();
+        if code == 429 {
+            assert_eq!(res.status(), StatusCode::TooManyRequests);
+            assert_eq!(res.header("Retry-After").unwrap().as_str(), "60");
+        }
+        code
+    }
+
+    #[async_std::test]
+    async fn naming_someone_doesnt_spend_their_tokens() {
+        let app = TestApp::with_config(|config| config.rate_limit = strict());
+
+        for _ in 0..2 {
+            assert_eq!(post_from(&app, "203.0.113.1", "bob").await, 200);
+        }
+        assert_eq!(post_from(&app, "203.0.113.1", "bob").await, 429);
+        assert_eq!(post_from(&app, "203.0.113.2", "bob").await, 200);
+    }
+
+    #[test]
+    fn buckets_stay_bounded() {
+        let limiter = RateLimiter::new(strict());
+        let start = Instant::now();
+        let client = |i: usize| {
+            let ip = format!("10.{}.{}.{}", i >> 16, (i >> 8) & 255, i & 255);
+            (ip, start + Duration::from_millis(i as u64))
+        };
+        for i in 0..MAX_BUCKETS * 3 {
+            let (ip, now) = client(i);
+            limiter.take(Class::Write, ip, now).unwrap();
+            assert!(limiter.buckets.lock().unwrap().len() <= MAX_BUCKETS);
+        }
+
+        // The most recent clients keep their buckets.
+        let (ip, now) = client(MAX_BUCKETS * 3 - 1);
+        limiter.take(Class::Write, ip.clone(), now).unwrap();
+        assert!(limiter.take(Class::Write, ip, now).is_err());
+    }
+}
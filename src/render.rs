@@ -0,0 +1,250 @@
+use serde_json::{json, Value};
+use tide::{http::mime, Body, Request, Response, StatusCode};
+
+/// Representation chosen for a response, negotiated from the `Accept` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Html,
+    Csv,
+}
+
+impl Format {
+    pub fn from_request<S>(req: &Request<S>) -> Format {
+        Format::negotiate(req.header("Accept").map(|values| values.as_str()))
+    }
+
+    /// Picks the best supported format. Higher `q` wins, and on equal `q` a
+    /// concrete media type beats a wildcard. Defaults to plain text.
+    pub fn negotiate(accept: Option<&str>) -> Format {
+        let mut best: Option<((f32, bool), Format)> = None;
+
+        for part in accept.unwrap_or("").split(',') {
+            let mut params = part.split(';');
+            let media = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            let q = params
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q <= 0.0 {
+                continue;
+            }
+
+            let (format, concrete) = match media.as_str() {
+                "text/plain" => (Format::Text, true),
+                "application/json" => (Format::Json, true),
+                "text/html" => (Format::Html, true),
+                "text/csv" => (Format::Csv, true),
+                "*/*" | "text/*" => (Format::Text, false),
+                _ => continue,
+            };
+
+            let rank = (q, concrete);
+            if best.is_none_or(|(best_rank, _)| rank > best_rank) {
+                best = Some((rank, format));
+            }
+        }
+
+        best.map_or(Format::Text, |(_, format)| format)
+    }
+}
+
+/// A handler result that can be shown in every supported format.
+pub trait Render {
+    fn text(&self) -> String;
+    fn json(&self) -> Value;
+    fn html(&self) -> String {
+        format!("<p>{}</p>", escape_html(&self.text()))
+    }
+    fn csv(&self) -> String;
+}
+
+/// Plain status message, the most common reply.
+pub struct Message<'a>(pub &'a str);
+
+impl Render for Message<'_> {
+    fn text(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn json(&self) -> Value {
+        Value::from(self.0)
+    }
+
+    fn csv(&self) -> String {
+        csv_table(&["message"], &[vec![self.0.to_string()]])
+    }
+}
+
+/*
+200 - Ok
+400 - Bad Request
+403 - Forbidden
+405 - Method Not Allowed
+500 - Internal Server Error
+*/
+/// Answers with `code` in the body. Clients read the outcome from `code`,
+/// so the HTTP status stays 200 for everything but server errors, which
+/// proxies and monitoring need to see as such.
+pub fn reply<S>(req: &Request<S>, code: u16, body: &impl Render) -> tide::Result {
+    let status = match code {
+        500..=599 => StatusCode::try_from(code).unwrap_or(StatusCode::InternalServerError),
+        _ => StatusCode::Ok,
+    };
+    respond(req, status, code, body)
+}
+
+/// Like `reply`, with `status` as the HTTP status too. For answers that
+/// don't come from the API handlers, where clients and tools like `curl -f`
+/// only look at the status: rate limiting and the operator endpoints.
+pub fn reply_with_status<S>(
+    req: &Request<S>,
+    status: StatusCode,
+    body: &impl Render,
+) -> tide::Result {
+    respond(req, status, status as u16, body)
+}
+
+fn respond<S>(req: &Request<S>, status: StatusCode, code: u16, body: &impl Render) -> tide::Result {
+    let mut res = Response::new(status);
+
+    match Format::from_request(req) {
+        Format::Text => {
+            res.set_body(body.text());
+            res.set_content_type(mime::PLAIN);
+        }
+        Format::Json => {
+            res.set_body(Body::from_json(&json!({
+                "code": code,
+                "message": body.json()
+            }))?);
+        }
+        Format::Html => {
+            res.set_body(page(&body.html()));
+            res.set_content_type(mime::HTML);
+        }
+        Format::Csv => {
+            res.set_body(body.csv());
+            res.set_content_type("text/csv; charset=utf-8");
+        }
+    }
+
+    Ok(res)
+}
+
+pub fn page(content: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Secret Santa</title></head>\n<body>\n<nav><a href=\"/ui\">Secret Santa</a></nav>\n{content}\n</body>\n</html>\n"
+    )
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn html_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut out = String::from("<table>\n<tr>");
+    for header in headers {
+        out += &format!("<th>{}</th>", escape_html(header));
+    }
+    out += "</tr>\n";
+    for row in rows {
+        out += "<tr>";
+        for cell in row {
+            out += &format!("<td>{}</td>", escape_html(cell));
+        }
+        out += "</tr>\n";
+    }
+    out += "</table>";
+    out
+}
+
+pub fn csv_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut out = csv_row(headers.iter().copied());
+    for row in rows {
+        out += &csv_row(row.iter().map(String::as_str));
+    }
+    out
+}
+
+fn csv_row<'a>(cells: impl Iterator<Item = &'a str>) -> String {
+    let cells: Vec<String> = cells
+        .map(|cell| {
+            if cell.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", cell.replace('"', "\"\""))
+            } else {
+                cell.to_string()
+            }
+        })
+        .collect();
+    cells.join(",") + "\r\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Format;
+
+    #[test]
+    fn negotiate_defaults_to_text() {
+        assert_eq!(Format::negotiate(None), Format::Text);
+        assert_eq!(Format::negotiate(Some("")), Format::Text);
+        assert_eq!(Format::negotiate(Some("image/png")), Format::Text);
+        assert_eq!(Format::negotiate(Some("*/*")), Format::Text);
+    }
+
+    #[test]
+    fn negotiate_picks_concrete_types() {
+        assert_eq!(Format::negotiate(Some("application/json")), Format::Json);
+        assert_eq!(Format::negotiate(Some("text/html")), Format::Html);
+        assert_eq!(Format::negotiate(Some("text/csv")), Format::Csv);
+        assert_eq!(Format::negotiate(Some("TEXT/CSV")), Format::Csv);
+    }
+
+    #[test]
+    fn negotiate_prefers_higher_q() {
+        assert_eq!(
+            Format::negotiate(Some("text/html;q=0.5, application/json;q=0.9")),
+            Format::Json
+        );
+        assert_eq!(
+            Format::negotiate(Some("application/json; q=0.1, text/csv")),
+            Format::Csv
+        );
+    }
+
+    #[test]
+    fn negotiate_prefers_concrete_over_wildcard_on_equal_q() {
+        assert_eq!(
+            Format::negotiate(Some("*/*, application/json")),
+            Format::Json
+        );
+        // A browser's usual header.
+        assert_eq!(
+            Format::negotiate(Some(
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+            )),
+            Format::Html
+        );
+    }
+
+    #[test]
+    fn negotiate_skips_refused_types() {
+        assert_eq!(
+            Format::negotiate(Some("application/json;q=0, text/csv;q=0.2")),
+            Format::Csv
+        );
+        assert_eq!(Format::negotiate(Some("text/html;q=0")), Format::Text);
+    }
+}
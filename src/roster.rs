@@ -0,0 +1,444 @@
+//! Setting up a group from a CSV roster, and exporting the roster.
+//!
+//! The CSV starts with a header row. `name` is required, `email` and
+//! `exclusions` are optional and other columns are ignored, so an export
+//! can be edited and imported again. Exclusions are member names separated
+//! by `;`, people that member must not be drawn to gift. A row for someone
+//! already in the group updates their exclusions. Their email is left as it
+//! is, only they can change it, see `profile`.
+//!
+//! New members with an email get their token by mail, see `auth`.
+
+use std::collections::HashSet;
+
+use tide::Request;
+
+use crate::audit::Action;
+use crate::events::GroupEvent;
+use crate::journal::Event;
+use crate::profile::{Contact, Privacy};
+use crate::render::reply;
+use crate::{
+    is_person_exist, is_valid_name, not_saved, notify, persist, read_body, returnable_value, views,
+    Access, DataBase, Person, State,
+};
+
+struct Row {
+    /// Row number in the file, the header being row 1.
+    number: usize,
+    name: String,
+    email: Option<String>,
+    exclusions: Vec<String>,
+}
+
+/// Splits CSV text into records. Quoted fields may contain commas, line
+/// breaks and doubled quotes.
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\r' | '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("The CSV has an unterminated quote".to_string());
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn parse_rows(text: &str) -> Result<Vec<Row>, String> {
+    let records = parse_csv(text)?;
+    let Some((header, records)) = records.split_first() else {
+        return Err("The CSV is empty".to_string());
+    };
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(name))
+    };
+    let name_column = column("name").ok_or("The CSV has no name column")?;
+    let email_column = column("email");
+    let exclusions_column = column("exclusions");
+
+    let mut rows: Vec<Row> = Vec::new();
+    for (index, record) in records.iter().enumerate() {
+        let number = index + 2;
+        let cell = |column: Option<usize>| {
+            column
+                .and_then(|column| record.get(column))
+                .map_or("", |cell| cell.trim())
+        };
+        if record.iter().all(|cell| cell.trim().is_empty()) {
+            continue;
+        }
+
+        let name = cell(Some(name_column));
+        if name.is_empty() {
+            return Err(format!("Row {number}: no name"));
+        }
+        if !is_valid_name(name) {
+            return Err(format!(
+                "Row {number}: names can't contain control characters"
+            ));
+        }
+        if rows.iter().any(|row| row.name == name) {
+            return Err(format!("Row {number}: \"{name}\" is listed twice"));
+        }
+        let email = cell(email_column);
+        if !email.is_empty() && !notify::is_valid_email(email) {
+            return Err(format!("Row {number}: bad email"));
+        }
+        let mut exclusions: Vec<String> = Vec::new();
+        for excluded in cell(exclusions_column).split(';').map(str::trim) {
+            if !excluded.is_empty() && !exclusions.iter().any(|e| e == excluded) {
+                exclusions.push(excluded.to_string());
+            }
+        }
+
+        rows.push(Row {
+            number,
+            name: name.to_string(),
+            email: Some(email.to_string()).filter(|email| !email.is_empty()),
+            exclusions,
+        });
+    }
+    Ok(rows)
+}
+
+/// A member the import added.
+struct Joined {
+    name: String,
+    /// Their token, when they have an email to send it to.
+    token: Option<String>,
+}
+
+/// Checks every row before changing anything, so a bad file leaves the
+/// group as it was. Returns the new members.
+fn apply(
+    database: &mut DataBase,
+    name: &str,
+    group_name: &str,
+    rows: Vec<Row>,
+) -> Result<Vec<Joined>, (String, u16)> {
+    let is_elsewhere: Vec<bool> = rows
+        .iter()
+        .map(|row| is_person_exist(&database.groups, &row.name))
+        .collect();
+    let group = database
+        .groups
+        .values_mut()
+        .find(|g| g.name == group_name)
+        .ok_or(("Group with that name does not exist".to_string(), 400))?;
+    if !group.is_admin(name) {
+        return Err(("Only the administrator can import members".to_string(), 403));
+    }
+    if group.closed {
+        return Err(("Reopen the group before importing members".to_string(), 400));
+    }
+
+    let mut members: HashSet<&str> = group.people.iter().map(|p| p.name.as_str()).collect();
+    for (row, is_elsewhere) in rows.iter().zip(is_elsewhere) {
+        if is_elsewhere && !members.contains(row.name.as_str()) {
+            return Err((
+                format!(
+                    "Row {}: \"{}\" is already in another group",
+                    row.number, row.name
+                ),
+                400,
+            ));
+        }
+    }
+    members.extend(rows.iter().map(|row| row.name.as_str()));
+    for row in &rows {
+        if let Some(excluded) = row
+            .exclusions
+            .iter()
+            .find(|e| **e == row.name || !members.contains(e.as_str()))
+        {
+            return Err((
+                format!(
+                    "Row {}: \"{}\" can't be excluded, it's not another member",
+                    row.number, excluded
+                ),
+                400,
+            ));
+        }
+    }
+
+    let mut joined = Vec::new();
+    for row in rows {
+        match group.people.iter_mut().find(|p| p.name == row.name) {
+            Some(person) => person.exclusions = row.exclusions,
+            None => {
+                let mut person = Person {
+                    name: row.name,
+                    santa_to: String::new(),
+                    access: Access::User,
+                    email: row.email,
+                    gift_bought: false,
+                    exclusions: row.exclusions,
+                    display_name: None,
+                    contact: Contact::default(),
+                    privacy: Privacy::default(),
+                    address: None,
+                    token_hash: None,
+                };
+                let token = person.email.is_some().then(|| person.issue_token());
+                joined.push(Joined {
+                    name: person.name.clone(),
+                    token,
+                });
+                group.people.push(person);
+            }
+        }
+    }
+    Ok(joined)
+}
+
+pub async fn import(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
+    struct Data {
+        name: String,
+        group_name: String,
+        csv: String,
+    }
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() || data.csv.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+    let rows = match parse_rows(&data.csv) {
+        Ok(rows) => rows,
+        Err(text) => return returnable_value(&req, &text, 400),
+    };
+    let total = rows.len();
+
+    let state = req.state();
+    let mut guard = state.database.lock().unwrap();
+
+    let created_at = guard
+        .groups
+        .values()
+        .find(|g| g.name == data.group_name)
+        .and_then(|g| g.created_at);
+    let joined = match apply(&mut guard, &data.name, &data.group_name, rows) {
+        Ok(joined) => joined,
+        Err((text, code)) => {
+            state.audit.record(
+                Some(&data.name),
+                Action::Import,
+                &data.group_name,
+                created_at,
+                None,
+                Err(&text),
+            );
+            return returnable_value(&req, &text, code);
+        }
+    };
+
+    let saved = persist(state, &mut guard, &data.group_name, Event::MembersImported);
+    state.audit.record_saved(
+        Some(&data.name),
+        Action::Import,
+        &data.group_name,
+        created_at,
+        None,
+        &saved,
+    );
+    if saved.is_err() {
+        return not_saved(&req);
+    }
+
+    if let Some(group) = guard.groups.values().find(|g| g.name == data.group_name) {
+        for joined in &joined {
+            let person = group.people.iter().find(|p| p.name == joined.name);
+            if let (Some(person), Some(token)) = (person, &joined.token) {
+                state.mailer.token(&group.name, person, token);
+            }
+        }
+    }
+    for Joined { name, .. } in &joined {
+        state.publish(
+            &guard,
+            GroupEvent::MemberJoined {
+                group_name: data.group_name.clone(),
+                name: name.clone(),
+            },
+        );
+    }
+
+    returnable_value(
+        &req,
+        &format!(
+            "Members added: {}. Members updated: {}",
+            joined.len(),
+            total - joined.len()
+        ),
+        200,
+    )
+}
+
+/// The roster of a group for its admin. With `assignments` and the admin's
+/// token, the admin's own assignment is included too, nobody else's.
+pub async fn export(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
+    struct Data {
+        name: String,
+        group_name: String,
+        #[serde(default)]
+        assignments: bool,
+        #[serde(default)]
+        token: String,
+    }
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+
+    let state = req.state();
+    let guard = state.database.lock().unwrap();
+
+    let Some(group) = guard.groups.values().find(|g| g.name == data.group_name) else {
+        return returnable_value(&req, "Group with that name does not exist", 400);
+    };
+    if !group.is_admin(&data.name) {
+        return returnable_value(&req, "Only the administrator can export the roster", 403);
+    }
+
+    let gifts_to = if data.assignments && group.closed {
+        let person = group.people.iter().find(|p| p.name == data.name);
+        if !person.is_some_and(|p| p.has_token(&data.token)) {
+            return returnable_value(&req, "Wrong or missing token", 403);
+        }
+        match person.and_then(|p| state.vault.open(&group.name, &p.name, &p.santa_to)) {
+            Some(giftee) => Some(giftee),
+            None => return returnable_value(&req, "Failed to read the assignment", 500),
+        }
+    } else {
+        None
+    };
+
+    reply(
+        &req,
+        200,
+        &views::Roster {
+            group_name: &group.name,
+            people: &group.people,
+            requester: &data.name,
+            gifts_to: gifts_to.as_deref(),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::testing::TestApp;
+
+    #[async_std::test]
+    async fn import_keeps_the_email_of_existing_members() {
+        let app = TestApp::new();
+        app.group("party", &["alice"]).await;
+        let body = json!({ "name": "bob", "group_name": "party", "email": "bob@example.com" });
+        assert_eq!(app.post("/groups/join", body).await.0, 200);
+
+        let csv = "name,email\nbob,mallory@example.com\ncarol,carol@example.com\n";
+        let body = json!({ "name": "alice", "group_name": "party", "csv": csv });
+        let (code, message) = app.post("/groups/import", body).await;
+        assert_eq!(code, 200, "{message}");
+
+        let guard = app.state.database.lock().unwrap();
+        let people = &guard.groups.values().next().unwrap().people;
+        let email = |name: &str| {
+            people
+                .iter()
+                .find(|p| p.name == name)
+                .and_then(|p| p.email.clone())
+        };
+        assert_eq!(email("bob").as_deref(), Some("bob@example.com"));
+        assert_eq!(email("carol").as_deref(), Some("carol@example.com"));
+    }
+
+    #[async_std::test]
+    async fn import_refuses_names_with_control_characters() {
+        let app = TestApp::new();
+        app.group("party", &["alice"]).await;
+
+        let csv = "name,email\nbob,\n\"\0seed\",\n";
+        let body = json!({ "name": "alice", "group_name": "party", "csv": csv });
+        let (code, message) = app.post("/groups/import", body).await;
+        assert_eq!(code, 400);
+        assert!(message.to_string().contains("Row 3"), "{message}");
+
+        let guard = app.state.database.lock().unwrap();
+        assert_eq!(guard.groups.values().next().unwrap().people.len(), 1);
+    }
+
+    #[async_std::test]
+    async fn imported_members_with_an_email_get_a_token() {
+        let app = TestApp::new();
+        app.group("party", &["alice"]).await;
+
+        let csv = "name,email\nbob,bob@example.com\ncarol,\n";
+        let body = json!({ "name": "alice", "group_name": "party", "csv": csv });
+        assert_eq!(app.post("/groups/import", body).await.0, 200);
+
+        let guard = app.state.database.lock().unwrap();
+        let people = &guard.groups.values().next().unwrap().people;
+        let has_token = |name: &str| {
+            people
+                .iter()
+                .find(|p| p.name == name)
+                .unwrap()
+                .token_hash
+                .is_some()
+        };
+        assert!(has_token("bob"));
+        assert!(!has_token("carol"));
+    }
+
+    #[async_std::test]
+    async fn exported_assignments_need_the_token() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        app.draw("party", "alice").await;
+
+        let body = json!({ "name": "alice", "group_name": "party", "assignments": true });
+        let (code, message) = app.post("/groups/export", body.clone()).await;
+        assert_eq!(code, 200, "{message}");
+        assert_eq!(message["people"][0]["gifts_to"], "bob");
+
+        let mut body = body;
+        body["token"] = json!("");
+        assert_eq!(app.post("/groups/export", body).await.0, 403);
+    }
+}
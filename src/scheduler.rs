@@ -0,0 +1,381 @@
+//! Background task that runs automatic draws and nudges members before a
+//! group's deadlines.
+//!
+//! Every sent reminder is recorded in the group's `reminders_sent` and saved
+//! with the database, so a restart doesn't send it again.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_std::task;
+use chrono::{DateTime, Utc};
+
+use crate::audit::Action;
+use crate::events::GroupEvent;
+use crate::journal::Event;
+use crate::{persist, Access, DataBase, DrawOrigin, DrawStep, State};
+
+const TICK: Duration = Duration::from_secs(60);
+
+/// Reminders sent before the exchange date: key and days before.
+const EXCHANGE_REMINDERS: [(&str, i64); 2] = [("exchange_7d", 7), ("exchange_1d", 1)];
+const DRAW_REMINDER: (&str, i64) = ("draw_1d", 1);
+
+/// When the scheduler last woke up, for `/readyz`.
+#[derive(Clone, Default)]
+pub struct Heartbeat(Arc<Mutex<Option<DateTime<Utc>>>>);
+
+impl Heartbeat {
+    pub fn last(&self) -> Option<DateTime<Utc>> {
+        *self.0.lock().unwrap()
+    }
+
+    /// Whether the loop woke up recently enough to still be running.
+    pub fn is_alive(&self, now: DateTime<Utc>) -> bool {
+        self.last()
+            .is_some_and(|last| now - last <= chrono::Duration::from_std(TICK * 2).unwrap())
+    }
+}
+
+pub fn start(state: State) {
+    task::spawn(async move {
+        loop {
+            *state.heartbeat.0.lock().unwrap() = Some(Utc::now());
+            {
+                let mut guard = state.database.lock().unwrap();
+                for (group_name, event) in tick(&state, &mut guard, Utc::now()) {
+                    let created_at = guard
+                        .groups
+                        .values()
+                        .find(|g| g.name == group_name)
+                        .and_then(|g| g.created_at);
+                    let saved = persist(&state, &mut guard, &group_name, event);
+                    if let Some(action) = audited(event) {
+                        state.audit.record_saved(
+                            None,
+                            action,
+                            &group_name,
+                            created_at,
+                            None,
+                            &saved,
+                        );
+                    }
+                    // The failed write rolled back every unsaved change of
+                    // this tick, the next tick does them again.
+                    if saved.is_err() {
+                        break;
+                    }
+                }
+            }
+            task::sleep(TICK).await;
+        }
+    });
+}
+
+/// The audit log action of a change the scheduler made, for those it has
+/// one. A failed draw is audited by `auto_draw` with the reason.
+fn audited(event: Event) -> Option<Action> {
+    match event {
+        Event::DrawCommitted => Some(Action::CommitDraw),
+        Event::DrawCompleted => Some(Action::SetSantas),
+        _ => None,
+    }
+}
+
+fn is_due(deadline: DateTime<Utc>, days_before: i64, now: DateTime<Utc>) -> bool {
+    now < deadline && now >= deadline - chrono::Duration::days(days_before)
+}
+
+/// Runs everything that is due at `now`. Returns the groups that changed and
+/// how.
+fn tick(state: &State, database: &mut DataBase, now: DateTime<Utc>) -> Vec<(String, Event)> {
+    let mut changes = auto_draw(state, database, now);
+    changes.extend(remind(state, database, now));
+    changes
+}
+
+/// Runs the draw in open groups whose `auto_draw_at` has passed, the same way
+/// `/groups/set_santas` does: the first tick publishes the commitment when
+/// there is none yet, the next one draws. A draw that can't be done is not
+/// retried, the admins are told instead.
+fn auto_draw(state: &State, database: &mut DataBase, now: DateTime<Utc>) -> Vec<(String, Event)> {
+    let mut changes = Vec::new();
+    let mut published = Vec::new();
+
+    for group in database.groups.values_mut() {
+        let Some(auto_draw_at) = group.auto_draw_at.filter(|at| !group.closed && *at <= now) else {
+            continue;
+        };
+
+        match group.draw(DrawOrigin::System, now, &state.vault) {
+            Ok(DrawStep::Committed(commitment)) => {
+                changes.push((group.name.clone(), Event::DrawCommitted));
+                published.push(GroupEvent::DrawCommitted {
+                    group_name: group.name.clone(),
+                    commitment,
+                });
+            }
+            Ok(DrawStep::Drawn(commitment)) => {
+                state.mailer.draw(group);
+                changes.push((group.name.clone(), Event::DrawCompleted));
+                published.push(GroupEvent::DrawCompleted {
+                    group_name: group.name.clone(),
+                    commitment,
+                });
+            }
+            Err(text) => {
+                tracing::warn!(group = %group.name, reason = text, "Automatic draw failed");
+                state.audit.record(
+                    None,
+                    Action::SetSantas,
+                    &group.name,
+                    group.created_at,
+                    None,
+                    Err(text),
+                );
+                group.auto_draw_at = None;
+                changes.push((group.name.clone(), Event::DrawFailed));
+                let note = format!(
+                    "The automatic draw in group \"{}\" planned for {} failed: {}.",
+                    group.name,
+                    auto_draw_at.format("%Y-%m-%d %H:%M UTC"),
+                    text
+                );
+                for admin in group
+                    .people
+                    .iter()
+                    .filter(|p| matches!(p.access, Access::Admin))
+                {
+                    state.mailer.reminder(group, admin, &note);
+                }
+            }
+        }
+    }
+
+    for event in published {
+        state.publish(database, event);
+    }
+
+    changes
+}
+
+fn remind(state: &State, database: &mut DataBase, now: DateTime<Utc>) -> Vec<(String, Event)> {
+    let mut changes = Vec::new();
+
+    for group in database.groups.values_mut() {
+        let mut due = Vec::new();
+
+        if let Some(exchange_date) = group.exchange_date.filter(|_| group.closed) {
+            for (key, days) in EXCHANGE_REMINDERS {
+                if is_due(exchange_date, days, now)
+                    && !group.reminders_sent.iter().any(|k| k == key)
+                {
+                    due.push((key, days, exchange_date));
+                }
+            }
+        }
+        if let Some(draw_date) = group.draw_date.filter(|_| !group.closed) {
+            let (key, days) = DRAW_REMINDER;
+            if is_due(draw_date, days, now) && !group.reminders_sent.iter().any(|k| k == key) {
+                due.push((key, days, draw_date));
+            }
+        }
+
+        // Only the closest reminder is worth sending when several are due,
+        // e.g. after the service was down for a few days.
+        let Some(&(_, _, date)) = due.iter().min_by_key(|(_, days, _)| *days) else {
+            continue;
+        };
+
+        for person in &group.people {
+            let note = if group.closed {
+                let mut note = format!(
+                    "The gift exchange in group \"{}\" is on {}.",
+                    group.name,
+                    date.format("%Y-%m-%d %H:%M UTC")
+                );
+                if !person.gift_bought {
+                    note += "\nYou still haven't marked your gift bought.";
+                }
+                note
+            } else {
+                format!(
+                    "The draw in group \"{}\" happens on {}.",
+                    group.name,
+                    date.format("%Y-%m-%d %H:%M UTC")
+                )
+            };
+            state.mailer.reminder(group, person, &note);
+        }
+
+        for (key, _, _) in due {
+            group.reminders_sent.push(key.to_string());
+        }
+        changes.push((group.name.clone(), Event::RemindersSent));
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use chrono::{DateTime, Duration, Utc};
+    use serde_json::json;
+
+    use super::tick;
+    use crate::config::{Config, MailConfig};
+    use crate::journal::Event;
+    use crate::testing::{self, TestApp};
+    use crate::{persist, State};
+
+    /// Mails go to `mail.log` next to the database.
+    fn config(dir: &Path) -> Config {
+        Config {
+            mail: MailConfig::Log {
+                path: Some(dir.join("mail.log")),
+            },
+            ..testing::config(dir)
+        }
+    }
+
+    /// A closed group of alice and bob exchanging gifts at `exchange_date`.
+    /// Both have an email, and only bob has marked the gift bought.
+    async fn closed_group(exchange_date: DateTime<Utc>) -> TestApp {
+        let app = TestApp::with_config(|c| *c = config(c.database.parent().unwrap()));
+        app.group("party", &["alice", "bob"]).await;
+        for name in ["alice", "bob"] {
+            let body = json!({
+                "name": name,
+                "group_name": "party",
+                "email": format!("{name}@example.com"),
+            });
+            assert_eq!(app.post("/groups/profile", body).await.0, 200);
+        }
+        app.draw("party", "alice").await;
+        let body = json!({ "name": "bob", "group_name": "party" });
+        assert_eq!(app.post("/groups/gift_bought", body).await.0, 200);
+        let mut guard = app.state.database.lock().unwrap();
+        guard.groups.values_mut().next().unwrap().exchange_date = Some(exchange_date);
+        drop(guard);
+        app
+    }
+
+    /// Runs `tick` and returns the events it reports.
+    fn run(state: &State, now: DateTime<Utc>) -> Vec<Event> {
+        let mut guard = state.database.lock().unwrap();
+        tick(state, &mut guard, now)
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect()
+    }
+
+    fn reminders_sent(app: &TestApp) -> Vec<String> {
+        let guard = app.state.database.lock().unwrap();
+        guard.groups.values().next().unwrap().reminders_sent.clone()
+    }
+
+    /// Waits for `count` mails in the log and returns them, by recipient.
+    async fn mails(app: &TestApp, count: usize) -> Vec<(String, String)> {
+        let mut mails = Vec::new();
+        for _ in 0..500 {
+            let log = std::fs::read_to_string(app.dir().join("mail.log")).unwrap_or_default();
+            mails = log
+                .split_terminator("----\n")
+                .filter(|mail| mail.contains("reminder"))
+                .map(|mail| {
+                    let to = mail.lines().next().unwrap().trim_start_matches("To: ");
+                    (to.to_string(), mail.to_string())
+                })
+                .collect();
+            if mails.len() >= count {
+                break;
+            }
+            async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        // Anything more would have been sent by now too.
+        async_std::task::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(mails.len(), count, "{mails:?}");
+        mails
+    }
+
+    #[async_std::test]
+    async fn exchange_reminders_go_out_a_week_and_a_day_before() {
+        let now = Utc::now();
+        let exchange_date = now + Duration::days(6);
+        let app = closed_group(exchange_date).await;
+
+        assert!(run(&app.state, now - Duration::days(2)).is_empty());
+        assert!(matches!(run(&app.state, now)[..], [Event::RemindersSent]));
+        assert_eq!(reminders_sent(&app), ["exchange_7d"]);
+        let sent = mails(&app, 2).await;
+        for (to, mail) in &sent {
+            let unbought = mail.contains("You still haven't marked your gift bought.");
+            assert_eq!(unbought, to == "alice@example.com", "{mail}");
+        }
+
+        assert!(run(&app.state, now + Duration::hours(1)).is_empty());
+        let day_before = exchange_date - Duration::hours(12);
+        assert!(matches!(
+            run(&app.state, day_before)[..],
+            [Event::RemindersSent]
+        ));
+        assert_eq!(reminders_sent(&app), ["exchange_7d", "exchange_1d"]);
+        assert!(run(&app.state, day_before + Duration::hours(1)).is_empty());
+        assert!(run(&app.state, exchange_date + Duration::hours(1)).is_empty());
+        mails(&app, 4).await;
+    }
+
+    #[async_std::test]
+    async fn only_the_closest_reminder_goes_out() {
+        let now = Utc::now();
+        let app = closed_group(now + Duration::hours(12)).await;
+
+        assert!(matches!(run(&app.state, now)[..], [Event::RemindersSent]));
+        assert_eq!(reminders_sent(&app), ["exchange_7d", "exchange_1d"]);
+        assert!(run(&app.state, now + Duration::hours(1)).is_empty());
+        mails(&app, 2).await;
+    }
+
+    #[async_std::test]
+    async fn sent_reminders_are_not_sent_again_after_a_restart() {
+        let now = Utc::now();
+        let app = closed_group(now + Duration::days(6)).await;
+        {
+            let mut guard = app.state.database.lock().unwrap();
+            for (group_name, event) in tick(&app.state, &mut guard, now) {
+                persist(&app.state, &mut guard, &group_name, event).unwrap();
+            }
+        }
+        mails(&app, 2).await;
+
+        let restarted = State::open(&config(app.dir())).unwrap();
+        assert!(run(&restarted, now + Duration::hours(1)).is_empty());
+        mails(&app, 2).await;
+    }
+
+    #[async_std::test]
+    async fn automatic_draw_publishes_the_commitment_a_tick_ahead() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        let now = Utc::now();
+        let mut guard = app.state.database.lock().unwrap();
+        let group = guard.groups.values_mut().next().unwrap();
+        group.auto_draw_at = Some(now - Duration::minutes(1));
+
+        let changes = tick(&app.state, &mut guard, now);
+        assert!(matches!(changes[..], [(_, Event::DrawCommitted)]));
+        let group = guard.groups.values().next().unwrap();
+        assert!(!group.closed);
+        let commitment = group.draw_proof.as_ref().unwrap().commitment.clone();
+
+        let changes = tick(&app.state, &mut guard, now + Duration::minutes(1));
+        assert!(matches!(changes[..], [(_, Event::DrawCompleted)]));
+        let group = guard.groups.values().next().unwrap();
+        assert!(group.closed);
+        assert_eq!(group.draw_proof.as_ref().unwrap().commitment, commitment);
+    }
+}
@@ -0,0 +1,187 @@
+//! CORS for browser frontends on other origins, and security headers on
+//! every response.
+
+use tide::{
+    http::{headers::HeaderValue, Url},
+    security::{CorsMiddleware, Origin},
+    Middleware, Next, Request,
+};
+
+use crate::config::{Config, SecurityHeadersConfig};
+use crate::State;
+
+/// Builds the CORS middleware, or `None` when no origin is allowed.
+///
+/// Browsers send `Origin` with form posts from our own pages too, and
+/// `CorsMiddleware` refuses origins that aren't listed, so the service's own
+/// origin is always allowed.
+pub fn cors(config: &Config) -> std::io::Result<Option<CorsMiddleware>> {
+    let cors = &config.cors;
+    if cors.allowed_origins.is_empty() {
+        return Ok(None);
+    }
+
+    let invalid = |what: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Bad CORS {what} in config"),
+        )
+    };
+
+    let origin = if cors.allowed_origins.iter().any(|o| o == "*") {
+        if cors.allow_credentials {
+            return Err(invalid("origins, credentials need explicit origins"));
+        }
+        Origin::Any
+    } else {
+        let mut origins = cors.allowed_origins.clone();
+        if let Ok(url) = Url::parse(&config.public_url) {
+            origins.push(url.origin().ascii_serialization());
+        }
+        Origin::List(origins)
+    };
+    let methods: HeaderValue = cors
+        .allowed_methods
+        .join(", ")
+        .parse()
+        .map_err(|_| invalid("methods"))?;
+    let max_age: HeaderValue = cors
+        .max_age
+        .to_string()
+        .parse()
+        .map_err(|_| invalid("max age"))?;
+
+    Ok(Some(
+        CorsMiddleware::new()
+            .allow_origin(origin)
+            .allow_methods(methods)
+            .allow_credentials(cors.allow_credentials)
+            .max_age(max_age),
+    ))
+}
+
+pub struct SecurityHeaders {
+    config: SecurityHeadersConfig,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: SecurityHeadersConfig) -> SecurityHeaders {
+        SecurityHeaders { config }
+    }
+}
+
+#[tide::utils::async_trait]
+impl Middleware<State> for SecurityHeaders {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let mut res = next.run(req).await;
+
+        res.insert_header("X-Content-Type-Options", "nosniff");
+        for (name, value) in [
+            (
+                "Content-Security-Policy",
+                &self.config.content_security_policy,
+            ),
+            ("X-Frame-Options", &self.config.frame_options),
+            ("Referrer-Policy", &self.config.referrer_policy),
+        ] {
+            if !value.is_empty() {
+                res.insert_header(name, value.as_str());
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{Method, Request, Response};
+
+    use super::cors;
+    use crate::testing::{self, url, TestApp};
+
+    fn allowing(origins: &[&str]) -> TestApp {
+        TestApp::with_config(|c| {
+            c.public_url = "https://santa.example.com".to_string();
+            c.cors.allowed_origins = origins.iter().map(|o| o.to_string()).collect();
+        })
+    }
+
+    async fn from(app: &TestApp, method: Method, origin: &str) -> Response {
+        let mut req = Request::new(method, url("/"));
+        req.insert_header("Origin", origin);
+        if method == Method::Options {
+            req.insert_header("Access-Control-Request-Method", "POST");
+        }
+        app.respond(req).await
+    }
+
+    fn allowed_origin(res: &Response) -> Option<String> {
+        res.header("Access-Control-Allow-Origin")
+            .map(|value| value.as_str().to_string())
+    }
+
+    #[async_std::test]
+    async fn only_listed_origins_and_our_own_are_allowed() {
+        let app = allowing(&["https://frontend.example.com"]);
+        for origin in ["https://frontend.example.com", "https://santa.example.com"] {
+            for method in [Method::Get, Method::Options] {
+                let res = from(&app, method, origin).await;
+                assert!(res.status().is_success(), "{method} from {origin}");
+                assert_eq!(allowed_origin(&res).as_deref(), Some(origin));
+            }
+        }
+
+        for method in [Method::Get, Method::Options] {
+            let res = from(&app, method, "https://evil.example.com").await;
+            assert!(!res.status().is_success(), "{method}");
+            assert_eq!(allowed_origin(&res), None);
+        }
+    }
+
+    #[async_std::test]
+    async fn any_origin_is_allowed_with_a_star() {
+        let app = allowing(&["*"]);
+        let res = from(&app, Method::Get, "https://anyone.example.com").await;
+        assert!(res.status().is_success());
+        assert_eq!(allowed_origin(&res).as_deref(), Some("*"));
+
+        let app = TestApp::new();
+        let res = from(&app, Method::Get, "https://anyone.example.com").await;
+        assert!(res.status().is_success());
+        assert_eq!(allowed_origin(&res), None);
+    }
+
+    #[test]
+    fn credentials_need_explicit_origins() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = testing::config(dir.path());
+        config.cors.allow_credentials = true;
+        config.cors.allowed_origins = vec!["*".to_string()];
+        assert!(cors(&config).is_err());
+
+        config.cors.allowed_origins = vec!["https://frontend.example.com".to_string()];
+        assert!(cors(&config).unwrap().is_some());
+    }
+
+    #[async_std::test]
+    async fn responses_carry_the_security_headers() {
+        let app = TestApp::new();
+        for res in [app.get("/").await, app.get("/no-such-page").await] {
+            assert_eq!(res["X-Content-Type-Options"], "nosniff");
+            assert_eq!(res["X-Frame-Options"], "DENY");
+            assert_eq!(res["Referrer-Policy"], "same-origin");
+            let policy = res["Content-Security-Policy"].as_str();
+            assert!(policy.contains("frame-ancestors 'none'"), "{policy}");
+        }
+
+        let app = TestApp::with_config(|c| c.security_headers.frame_options = String::new());
+        let res = app.get("/").await;
+        assert!(res.header("X-Frame-Options").is_none());
+        assert_eq!(res["Referrer-Policy"], "same-origin");
+
+        let app = TestApp::with_config(|c| c.security_headers.enabled = false);
+        let res = app.get("/").await;
+        assert!(res.header("X-Content-Type-Options").is_none());
+        assert!(res.header("Content-Security-Policy").is_none());
+    }
+}
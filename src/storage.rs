@@ -0,0 +1,189 @@
+//! Persistence of the database to `data.base`, or the file set in the
+//! config, in the versioned format described in `migrations`.
+
+use std::{collections::HashMap, fs::File, path::Path, time::Instant};
+
+use chrono::Utc;
+
+use crate::{metrics, migrations, DataBase};
+
+#[derive(serde::Serialize)]
+struct Envelope<'a> {
+    version: u64,
+    data: &'a DataBase,
+}
+
+#[tracing::instrument(name = "storage.load", err)]
+pub fn load(path: &Path) -> std::io::Result<DataBase> {
+    match File::open(path) {
+        Ok(file) => {
+            let read_error = |err: String| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Failed to read from database file. {err}"),
+                )
+            };
+            let file: serde_json::Value =
+                serde_json::from_reader(file).map_err(|err| read_error(err.to_string()))?;
+
+            let version = migrations::version(&file);
+            if version < migrations::CURRENT_VERSION {
+                backup(path, version)?;
+            }
+            let database = decode(file).map_err(read_error)?;
+            if version < migrations::CURRENT_VERSION {
+                save(path, &database)?;
+            }
+            Ok(database)
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            tracing::info!("Database file not found. Creating one");
+
+            let database = DataBase {
+                groups: HashMap::new(),
+            };
+            save(path, &database)?;
+            Ok(database)
+        }
+        Err(err) => Err(std::io::Error::new(
+            err.kind(),
+            format!("Failed to open database file. {err}"),
+        )),
+    }
+}
+
+/// Copies the file aside before migrating it, so a bad migration can be
+/// undone by hand.
+fn backup(path: &Path, version: u64) -> std::io::Result<()> {
+    let backup_file = with_suffix(
+        path,
+        &format!(".v{version}.{}.bak", Utc::now().format("%Y%m%dT%H%M%SZ")),
+    );
+    tracing::info!(backup_file = %backup_file.display(), "Backing up database file before migration");
+    std::fs::copy(path, &backup_file)
+        .map(|_| ())
+        .map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!("Failed to back up database file. {err}"),
+            )
+        })
+}
+
+/// Checks that files can be created next to the database, which `save`
+/// needs to replace it.
+pub fn check_writable(path: &Path) -> std::io::Result<()> {
+    let probe_file = with_suffix(path, ".probe");
+    std::fs::write(&probe_file, b"").and_then(|_| std::fs::remove_file(&probe_file))
+}
+
+/// `data.base` becomes `data.base<suffix>`.
+fn with_suffix(path: &Path, suffix: &str) -> std::path::PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    name.into()
+}
+
+/// Parses a file in any known version of the format, migrating it first.
+pub fn decode(file: serde_json::Value) -> Result<DataBase, String> {
+    let data = migrations::migrate(file)?;
+    serde_json::from_value(data).map_err(|err| err.to_string())
+}
+
+/// Serializes the database in the current version of the format.
+pub fn encode(database: &DataBase) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(&Envelope {
+        version: migrations::CURRENT_VERSION,
+        data: database,
+    })
+}
+
+/// Writes the whole database. The file is replaced atomically, so a crash
+/// mid-write leaves the previous snapshot intact.
+#[tracing::instrument(name = "storage.save", skip_all, err)]
+pub fn save(path: &Path, database: &DataBase) -> std::io::Result<()> {
+    let temp_file = with_suffix(path, ".tmp");
+    let data = encode(database).map_err(|err| {
+        let err = std::io::Error::from(err);
+        std::io::Error::new(
+            err.kind(),
+            format!("Failed to write to database file. {err}"),
+        )
+    })?;
+    let size = data.len();
+    let start = Instant::now();
+    let result = std::fs::write(&temp_file, data).and_then(|_| std::fs::rename(&temp_file, path));
+    metrics::persisted(start.elapsed(), result.is_ok());
+    result
+        .inspect(|_| tracing::debug!(bytes = size, "Database saved"))
+        .map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!("Failed to write to database file. {err}"),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::{json, Value};
+
+    use super::load;
+    use crate::migrations;
+
+    fn group() -> Value {
+        json!({
+            "name": "party",
+            "people": [{ "name": "alice", "santa_to": "", "access": "admin" }],
+            "closed": false
+        })
+    }
+
+    #[test]
+    fn unversioned_files_are_backed_up_and_upgraded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.base");
+        let original = json!({ "groups": { "3": group() } }).to_string();
+        fs::write(&path, &original).unwrap();
+
+        let database = load(&path).unwrap();
+        assert_eq!(database.groups[&3].people[0].name, "alice");
+
+        let saved: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved["version"], json!(migrations::CURRENT_VERSION));
+        assert_eq!(saved["data"]["groups"]["3"]["name"], "party");
+
+        let backups: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("data.base.v0.") && name.ends_with(".bak"))
+            .collect();
+        assert_eq!(backups.len(), 1, "{backups:?}");
+        let backup = fs::read_to_string(dir.path().join(&backups[0])).unwrap();
+        assert_eq!(backup, original);
+
+        // Loading again finds the current version and changes nothing.
+        load(&path).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn files_from_a_newer_build_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.base");
+        let newer = json!({
+            "version": migrations::CURRENT_VERSION + 1,
+            "data": { "groups": {} }
+        })
+        .to_string();
+        fs::write(&path, &newer).unwrap();
+
+        let err = load(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("only knows up to"), "{err}");
+        assert_eq!(fs::read_to_string(&path).unwrap(), newer);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,146 @@
+//! Runs the service in-process for tests, with its files in a temporary
+//! directory.
+
+use std::{collections::HashMap, path::Path, sync::Mutex};
+
+use serde_json::Value;
+use tempfile::TempDir;
+use tide::http::{Method, Request, Response, Url};
+
+use crate::config::{BackupConfig, Config, RateLimitConfig};
+use crate::{app, State};
+
+pub const OPERATOR_TOKEN: &str = "operator-token";
+
+/// Keeps every file in `dir`, sends no mail and takes no scheduled backups.
+pub fn config(dir: &Path) -> Config {
+    Config {
+        database: dir.join("data.base"),
+        journal: dir.join("data.journal"),
+        assignment_key: dir.join("santa.key"),
+        audit_log: dir.join("audit.log"),
+        backup: BackupConfig {
+            directory: dir.join("backups"),
+            every_hours: 0,
+            token: Some(OPERATOR_TOKEN.to_string()),
+            ..BackupConfig::default()
+        },
+        rate_limit: RateLimitConfig {
+            enabled: false,
+            ..RateLimitConfig::default()
+        },
+        ..Config::default()
+    }
+}
+
+pub struct TestApp {
+    pub state: State,
+    pub server: tide::Server<State>,
+    /// Removed with the files in it when the test ends.
+    dir: TempDir,
+    /// Tokens handed out by `/groups/create` and `/groups/join`, by name.
+    tokens: Mutex<HashMap<String, String>>,
+}
+
+impl TestApp {
+    pub fn new() -> TestApp {
+        TestApp::with_config(|_| {})
+    }
+
+    pub fn with_config(change: impl FnOnce(&mut Config)) -> TestApp {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config(dir.path());
+        change(&mut config);
+        let state = State::open(&config).unwrap();
+        let server = app(state.clone(), &config).unwrap();
+        TestApp {
+            state,
+            server,
+            dir,
+            tokens: Mutex::default(),
+        }
+    }
+
+    /// Where `config` put the files.
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Poisons the database lock, as a handler panicking while holding it
+    /// would.
+    pub fn poison_database(&self) {
+        let database = self.state.database.clone();
+        let panicked = std::thread::spawn(move || {
+            let _guard = database.lock().unwrap();
+            panic!("poisoning the database lock");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(self.state.database.is_poisoned());
+    }
+
+    pub async fn respond(&self, req: Request) -> Response {
+        self.server.respond(req).await.unwrap()
+    }
+
+    pub async fn get(&self, path: &str) -> Response {
+        self.respond(Request::new(Method::Get, url(path))).await
+    }
+
+    /// The token `name` got from creating or joining a group.
+    pub fn token(&self, name: &str) -> String {
+        self.tokens.lock().unwrap()[name].clone()
+    }
+
+    /// Posts `body` as JSON and returns the `code` and `message` of the
+    /// answer.
+    pub async fn post(&self, path: &str, body: Value) -> (u64, Value) {
+        let name = body["name"].as_str().map(str::to_string);
+        let answer = self.post_accepting(path, body, "application/json").await;
+        let answer: Value = serde_json::from_str(&answer).unwrap();
+        if let (Some(name), Some(token)) = (name, answer["message"]["token"].as_str()) {
+            self.tokens.lock().unwrap().insert(name, token.to_string());
+        }
+        (answer["code"].as_u64().unwrap(), answer["message"].clone())
+    }
+
+    /// Posts `body` as JSON and returns the answer in the `accept` format.
+    /// Unless `body` has a `token`, the one its `name` got is sent along.
+    pub async fn post_accepting(&self, path: &str, mut body: Value, accept: &str) -> String {
+        let token = body["name"]
+            .as_str()
+            .and_then(|name| self.tokens.lock().unwrap().get(name).cloned());
+        if let (Some(token), Some(fields)) = (token, body.as_object_mut()) {
+            fields.entry("token").or_insert(token.into());
+        }
+        let mut req = Request::new(Method::Post, url(path));
+        req.insert_header("Accept", accept);
+        req.set_body(body);
+        self.respond(req).await.body_string().await.unwrap()
+    }
+
+    /// Creates a group administered by the first of `members` and joins
+    /// the others to it.
+    pub async fn group(&self, group_name: &str, members: &[&str]) {
+        let (admin, others) = members.split_first().unwrap();
+        let body = serde_json::json!({ "name": admin, "group_name": group_name });
+        assert_eq!(self.post("/groups/create", body).await.0, 200);
+        for name in others {
+            let body = serde_json::json!({ "name": name, "group_name": group_name });
+            assert_eq!(self.post("/groups/join", body).await.0, 200);
+        }
+    }
+
+    /// Publishes the draw commitment and runs the draw.
+    pub async fn draw(&self, group_name: &str, admin: &str) {
+        let body = serde_json::json!({ "name": admin, "group_name": group_name });
+        for _ in 0..2 {
+            let (code, message) = self.post("/groups/set_santas", body.clone()).await;
+            assert_eq!(code, 200, "{message}");
+        }
+    }
+}
+
+pub fn url(path: &str) -> Url {
+    Url::parse("http://santa.test").unwrap().join(path).unwrap()
+}
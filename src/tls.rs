@@ -0,0 +1,128 @@
+//! HTTPS listener, and a plain HTTP listener that sends clients over to it.
+
+use async_std::task;
+use tide::{http::Url, Redirect, Request};
+use tide_rustls::{TlsListener, TlsListenerBuilder};
+
+use crate::config::TlsConfig;
+use crate::State;
+
+pub fn listener(listen: &str, config: &TlsConfig) -> TlsListenerBuilder<State> {
+    TlsListener::build()
+        .addrs(listen.to_string())
+        .cert(&config.cert)
+        .key(&config.key)
+}
+
+/// Serves redirects from `listen` to the same path under `public_url`. They
+/// are 308s, so browsers resend form posts instead of turning them into GETs.
+pub fn redirect(listen: String, public_url: String) {
+    let base = match Url::parse(&public_url) {
+        Ok(url) if url.scheme() == "https" => url,
+        _ => {
+            tracing::error!(
+                public_url,
+                "public_url must be an https address to redirect HTTP to it"
+            );
+            return;
+        }
+    };
+
+    task::spawn(async move {
+        let mut server = tide::new();
+        let handler = move |req: Request<()>| {
+            let mut target = base.clone();
+            target.set_path(req.url().path());
+            target.set_query(req.url().query());
+            async move { Ok(Redirect::permanent(target)) }
+        };
+        server.at("/").all(handler.clone());
+        server.at("*").all(handler);
+
+        tracing::info!(listen, "Redirecting plain HTTP to HTTPS");
+        if let Err(err) = server.listen(listen).await {
+            tracing::error!(error = %err, "HTTP redirect listener failed");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::Arc, time::Duration};
+
+    use async_std::{net::TcpStream, task};
+
+    use crate::config::TlsConfig;
+    use crate::testing::TestApp;
+
+    /// A port nothing listens on right now.
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    async fn wait_for(addr: &str) {
+        for _ in 0..100 {
+            if TcpStream::connect(addr).await.is_ok() {
+                return;
+            }
+            task::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("nothing listens on {addr}");
+    }
+
+    #[async_std::test]
+    async fn serves_https_with_the_configured_certificate() {
+        let app = TestApp::new();
+        let dir = tempfile::tempdir().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let config = TlsConfig {
+            cert: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+            redirect_from: None,
+        };
+        std::fs::write(&config.cert, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&config.key, cert.serialize_private_key_pem()).unwrap();
+
+        let port = free_port();
+        let addr = format!("127.0.0.1:{port}");
+        task::spawn(app.server.clone().listen(super::listener(&addr, &config)));
+        wait_for(&addr).await;
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(cert.serialize_der().unwrap()))
+            .unwrap();
+        let tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let agent = ureq::AgentBuilder::new().tls_config(Arc::new(tls)).build();
+        let url = format!("https://localhost:{port}/healthz");
+        let status = task::spawn_blocking(move || agent.get(&url).call().unwrap().status()).await;
+        assert_eq!(status, 200);
+    }
+
+    #[async_std::test]
+    async fn plain_http_is_redirected_with_308() {
+        let addr = format!("127.0.0.1:{}", free_port());
+        super::redirect(addr.clone(), "https://santa.example".to_string());
+        wait_for(&addr).await;
+
+        let agent = ureq::AgentBuilder::new().redirects(0).build();
+        let url = format!("http://{addr}/groups/join?invite=abc");
+        let (status, location) = task::spawn_blocking(move || {
+            let res = agent.post(&url).call().unwrap();
+            (res.status(), res.header("Location").map(str::to_string))
+        })
+        .await;
+        assert_eq!(status, 308);
+        assert_eq!(
+            location.as_deref(),
+            Some("https://santa.example/groups/join?invite=abc")
+        );
+    }
+}
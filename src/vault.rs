@@ -0,0 +1,151 @@
+//! Encryption of assignments at rest.
+//!
+//! `Person::santa_to` only ever holds a sealed value, in memory and in
+//! `data.base`. The key lives in its own file, so reading the database alone
+//! doesn't reveal the draw. Every assignment is sealed separately and bound
+//! to its group and giver, so sealed values can't be swapped between members.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use base64::Engine;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+
+use crate::DataBase;
+
+const SEALED_PREFIX: &str = "v1:";
+const NONCE_LEN: usize = 24;
+
+#[derive(Clone)]
+pub struct Vault {
+    cipher: Arc<XChaCha20Poly1305>,
+}
+
+impl Vault {
+    /// Reads the hex encoded key, or generates one when the file is missing.
+    pub fn load_or_create(path: &Path) -> io::Result<Vault> {
+        let key = match fs::read_to_string(path) {
+            Ok(text) => hex::decode(text.trim())
+                .ok()
+                .filter(|key| key.len() == 32)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Assignment key {} is not 32 hex encoded bytes",
+                            path.display()
+                        ),
+                    )
+                })?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                tracing::info!(path = %path.display(), "Assignment key not found. Creating one");
+                let key = XChaCha20Poly1305::generate_key(&mut OsRng).to_vec();
+                write_private(path, hex::encode(&key).as_bytes()).map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!("Failed to create assignment key. {err}"),
+                    )
+                })?;
+                key
+            }
+            Err(err) => {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("Failed to read assignment key. {err}"),
+                ))
+            }
+        };
+
+        Ok(Vault {
+            cipher: Arc::new(XChaCha20Poly1305::new_from_slice(&key).unwrap()),
+        })
+    }
+
+    pub fn seal(&self, group_name: &str, giver: &str, giftee: &str) -> String {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(group_name, giver);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: giftee.as_bytes(),
+                    aad: &aad,
+                },
+            )
+            .expect("encryption never fails for in-memory buffers");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        format!(
+            "{SEALED_PREFIX}{}",
+            base64::engine::general_purpose::STANDARD.encode(sealed)
+        )
+    }
+
+    /// Returns the giftee, or `None` if the value was not sealed with this
+    /// key for this giver.
+    pub fn open(&self, group_name: &str, giver: &str, sealed: &str) -> Option<String> {
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(sealed.strip_prefix(SEALED_PREFIX)?)
+            .ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let aad = associated_data(group_name, giver);
+        let giftee = self
+            .cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .ok()?;
+        String::from_utf8(giftee).ok()
+    }
+
+    /// Seals assignments still stored in plain text by older versions.
+    /// Returns whether anything changed.
+    pub fn seal_plaintext(&self, database: &mut DataBase) -> bool {
+        let mut changed = false;
+        for group in database.groups.values_mut() {
+            for person in &mut group.people {
+                if !person.santa_to.is_empty() && !is_sealed(&person.santa_to) {
+                    person.santa_to = self.seal(&group.name, &person.name, &person.santa_to);
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+pub fn is_sealed(value: &str) -> bool {
+    value.starts_with(SEALED_PREFIX)
+}
+
+fn associated_data(group_name: &str, giver: &str) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(group_name.len() + giver.len() + 1);
+    aad.extend_from_slice(group_name.as_bytes());
+    aad.push(0);
+    aad.extend_from_slice(giver.as_bytes());
+    aad
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
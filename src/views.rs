@@ -0,0 +1,690 @@
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use crate::audit::Entry;
+use crate::draw::{DrawProof, EarlierDraw, Verification};
+use crate::render::{csv_table, escape_html, html_table, Render};
+use crate::{DataBase, Group, Person};
+
+/// A giver's own assignment, with the giftee's shipping address if they
+/// left one.
+pub struct Gifted<'a> {
+    pub santa_to: &'a str,
+    pub address: Option<&'a str>,
+}
+
+impl Render for Gifted<'_> {
+    fn text(&self) -> String {
+        match self.address {
+            Some(address) => format!(
+                "You secret santa to - {}\nShipping address:\n{}",
+                self.santa_to, address
+            ),
+            None => format!("You secret santa to - {}", self.santa_to),
+        }
+    }
+
+    fn json(&self) -> Value {
+        json!({ "gifted": self.santa_to, "address": self.address })
+    }
+
+    fn csv(&self) -> String {
+        csv_table(
+            &["gifted", "address"],
+            &[vec![
+                self.santa_to.to_string(),
+                self.address.unwrap_or_default().to_string(),
+            ]],
+        )
+    }
+}
+
+/// Answer to creating or joining a group, with the member's new token, see
+/// `auth`. It is only ever shown here.
+pub struct Welcome<'a> {
+    pub text: &'a str,
+    pub name: &'a str,
+    pub token: &'a str,
+}
+
+impl Render for Welcome<'_> {
+    fn text(&self) -> String {
+        format!(
+            "{}\nYour token: {}\nKeep it, you need it to see whom you gift",
+            self.text, self.token
+        )
+    }
+
+    fn json(&self) -> Value {
+        json!({ "text": self.text, "token": self.token })
+    }
+
+    fn html(&self) -> String {
+        format!(
+            "<p>{}</p>\n<p>Your token: <code>{}</code>. Keep it, you need it to see whom you gift.</p>\n\
+             <form method=\"post\" action=\"/ui/login\">\n\
+             <input type=\"hidden\" name=\"name\" value=\"{}\">\n\
+             <input type=\"hidden\" name=\"token\" value=\"{}\">\n\
+             <button type=\"submit\">Log in with it</button>\n</form>",
+            escape_html(self.text),
+            escape_html(self.token),
+            escape_html(self.name),
+            escape_html(self.token)
+        )
+    }
+
+    fn csv(&self) -> String {
+        csv_table(
+            &["message", "token"],
+            &[vec![self.text.to_string(), self.token.to_string()]],
+        )
+    }
+}
+
+/// The roster as group members see it. Assignments are never part of it,
+/// each giver only learns their own one from `/to-who-gift`. Email and
+/// contact note show up as the member's privacy settings allow.
+pub struct Members<'a> {
+    pub group_name: &'a str,
+    pub people: &'a [Person],
+    /// The member asking, who gave their token.
+    pub viewer: &'a str,
+}
+
+impl Members<'_> {
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.people
+            .iter()
+            .enumerate()
+            .map(|(id, person)| {
+                vec![
+                    id.to_string(),
+                    person.name.clone(),
+                    person.display_name.clone().unwrap_or_default(),
+                    format!("{:?}", person.access),
+                    person
+                        .email_for(self.viewer)
+                        .unwrap_or_default()
+                        .to_string(),
+                    person
+                        .contact_for(self.viewer)
+                        .unwrap_or_default()
+                        .to_string(),
+                ]
+            })
+            .collect()
+    }
+}
+
+impl Render for Members<'_> {
+    fn text(&self) -> String {
+        let mut out_message = String::new();
+        for (id, person) in self.people.iter().enumerate() {
+            out_message += format!("{}. Name: {}", id, person.name).as_str();
+            if let Some(display_name) = &person.display_name {
+                out_message += format!(" ({display_name})").as_str();
+            }
+            out_message += format!(". Access: {:?}", person.access).as_str();
+            if let Some(email) = person.email_for(self.viewer) {
+                out_message += format!(". Email: {email}").as_str();
+            }
+            if let Some(contact) = person.contact_for(self.viewer) {
+                out_message += format!(". Contact: {contact}").as_str();
+            }
+            out_message += "\n";
+        }
+        out_message
+    }
+
+    fn json(&self) -> Value {
+        let people: Vec<Value> = self
+            .people
+            .iter()
+            .map(|person| {
+                json!({
+                    "name": person.name,
+                    "display_name": person.display_name,
+                    "access": person.access,
+                    "email": person.email_for(self.viewer),
+                    "contact": person.contact_for(self.viewer)
+                })
+            })
+            .collect();
+        json!({
+            "group_name": self.group_name,
+            "people": people
+        })
+    }
+
+    fn html(&self) -> String {
+        format!(
+            "<h2>{}</h2>\n{}",
+            escape_html(self.group_name),
+            html_table(
+                &["Id", "Name", "Display name", "Access", "Email", "Contact"],
+                &self.rows()
+            )
+        )
+    }
+
+    fn csv(&self) -> String {
+        csv_table(
+            &["id", "name", "display_name", "access", "email", "contact"],
+            &self.rows(),
+        )
+    }
+}
+
+/// The full roster for admins, in the columns `/groups/import` reads, so
+/// an export can be edited and imported again. Emails follow the members'
+/// privacy settings like in `Members`, an import doesn't change them.
+/// Carries at most one assignment: the requester's own, when they ask for
+/// it.
+pub struct Roster<'a> {
+    pub group_name: &'a str,
+    pub people: &'a [Person],
+    pub requester: &'a str,
+    pub gifts_to: Option<&'a str>,
+}
+
+impl Roster<'_> {
+    fn gifts_to(&self, person: &Person) -> Option<&str> {
+        self.gifts_to.filter(|_| person.name == self.requester)
+    }
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.people
+            .iter()
+            .map(|person| {
+                vec![
+                    person.name.clone(),
+                    person
+                        .email_for(self.requester)
+                        .unwrap_or_default()
+                        .to_string(),
+                    person.exclusions.join(";"),
+                    format!("{:?}", person.access),
+                    self.gifts_to(person).unwrap_or_default().to_string(),
+                ]
+            })
+            .collect()
+    }
+}
+
+impl Render for Roster<'_> {
+    fn text(&self) -> String {
+        let mut out_message = String::new();
+        for (id, person) in self.people.iter().enumerate() {
+            out_message += format!(
+                "{}. Name: {}. Access: {:?}. Email: {}. Exclusions: {}\n",
+                id,
+                person.name,
+                person.access,
+                person.email_for(self.requester).unwrap_or("-"),
+                person.exclusions.join(", ")
+            )
+            .as_str();
+            if let Some(gifts_to) = self.gifts_to(person) {
+                out_message += format!("You secret santa to - {gifts_to}\n").as_str();
+            }
+        }
+        out_message
+    }
+
+    fn json(&self) -> Value {
+        let people: Vec<Value> = self
+            .people
+            .iter()
+            .map(|person| {
+                json!({
+                    "name": person.name,
+                    "email": person.email_for(self.requester),
+                    "exclusions": person.exclusions,
+                    "access": person.access,
+                    "gifts_to": self.gifts_to(person)
+                })
+            })
+            .collect();
+        json!({
+            "group_name": self.group_name,
+            "people": people
+        })
+    }
+
+    fn html(&self) -> String {
+        format!(
+            "<h2>{}</h2>\n{}",
+            escape_html(self.group_name),
+            html_table(
+                &["Name", "Email", "Exclusions", "Access", "Gifts to"],
+                &self.rows()
+            )
+        )
+    }
+
+    fn csv(&self) -> String {
+        csv_table(
+            &["name", "email", "exclusions", "access", "gifts_to"],
+            &self.rows(),
+        )
+    }
+}
+
+/// Public metadata of a group, all that non-members get to see.
+pub struct GroupSummary<'a> {
+    pub group: &'a Group,
+}
+
+impl GroupSummary<'_> {
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.group.name.clone(),
+            self.group.people.len().to_string(),
+            self.group.closed.to_string(),
+            self.commitment().unwrap_or_default().to_string(),
+        ]
+    }
+
+    fn commitment(&self) -> Option<&str> {
+        self.group
+            .draw_proof
+            .as_ref()
+            .map(|proof| proof.commitment.as_str())
+    }
+}
+
+impl Render for GroupSummary<'_> {
+    fn text(&self) -> String {
+        let mut out_message = format!(
+            "Group name: \"{}\". Persons: {}. Is closed: {}\n",
+            self.group.name,
+            self.group.people.len(),
+            self.group.closed
+        );
+        if let Some(commitment) = self.commitment() {
+            out_message += &format!("Draw commitment: {commitment}\n");
+        }
+        out_message
+    }
+
+    fn json(&self) -> Value {
+        json!({
+            "group_name": self.group.name,
+            "persons": self.group.people.len(),
+            "closed": self.group.closed,
+            "draw_commitment": self.commitment()
+        })
+    }
+
+    fn html(&self) -> String {
+        html_table(
+            &["Group name", "Persons", "Is closed", "Draw commitment"],
+            &[self.row()],
+        )
+    }
+
+    fn csv(&self) -> String {
+        csv_table(
+            &["group_name", "persons", "closed", "draw_commitment"],
+            &[self.row()],
+        )
+    }
+}
+
+/// Public metadata of every group.
+pub struct Groups<'a> {
+    pub database: &'a DataBase,
+}
+
+impl Groups<'_> {
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.database
+            .groups
+            .iter()
+            .map(|(id, group)| {
+                vec![
+                    id.to_string(),
+                    group.name.clone(),
+                    group.people.len().to_string(),
+                    group.closed.to_string(),
+                ]
+            })
+            .collect()
+    }
+}
+
+impl Render for Groups<'_> {
+    fn text(&self) -> String {
+        let mut out_message = String::from("Groups: \n");
+        for (id, group) in &self.database.groups {
+            out_message += format!(
+                "Id: {}. Group name: \"{}\". Persons: {}. Is closed: {}\n",
+                id,
+                group.name,
+                group.people.len(),
+                group.closed
+            )
+            .as_str();
+        }
+        out_message
+    }
+
+    fn json(&self) -> Value {
+        let groups: Vec<Value> = self
+            .database
+            .groups
+            .iter()
+            .map(|(id, group)| {
+                json!({
+                    "id": id,
+                    "name": group.name,
+                    "persons": group.people.len(),
+                    "closed": group.closed
+                })
+            })
+            .collect();
+        json!({ "groups": groups })
+    }
+
+    fn html(&self) -> String {
+        html_table(&["Id", "Group name", "Persons", "Is closed"], &self.rows())
+    }
+
+    fn csv(&self) -> String {
+        csv_table(&["id", "group_name", "persons", "closed"], &self.rows())
+    }
+}
+
+pub struct WebhookAdded<'a> {
+    pub url: &'a str,
+    pub secret: &'a str,
+}
+
+impl Render for WebhookAdded<'_> {
+    fn text(&self) -> String {
+        format!(
+            "Webhook added. Payloads to {} are signed with secret {}",
+            self.url, self.secret
+        )
+    }
+
+    fn json(&self) -> Value {
+        json!({ "url": self.url, "secret": self.secret })
+    }
+
+    fn csv(&self) -> String {
+        csv_table(
+            &["url", "secret"],
+            &[vec![self.url.to_string(), self.secret.to_string()]],
+        )
+    }
+}
+
+pub struct WebhookList<'a> {
+    pub urls: &'a [String],
+}
+
+impl Render for WebhookList<'_> {
+    fn text(&self) -> String {
+        let mut out_message = String::from("Webhooks: \n");
+        for url in self.urls {
+            out_message += format!("{url}\n").as_str();
+        }
+        out_message
+    }
+
+    fn json(&self) -> Value {
+        json!({ "webhooks": self.urls })
+    }
+
+    fn html(&self) -> String {
+        let rows: Vec<Vec<String>> = self.urls.iter().map(|url| vec![url.clone()]).collect();
+        html_table(&["Url"], &rows)
+    }
+
+    fn csv(&self) -> String {
+        let rows: Vec<Vec<String>> = self.urls.iter().map(|url| vec![url.clone()]).collect();
+        csv_table(&["url"], &rows)
+    }
+}
+
+/// A draw's commitment, and once the seed is revealed, the outcome of
+/// checking the draw against it.
+pub struct DrawVerification<'a> {
+    pub group_name: &'a str,
+    /// The current commitment, `None` after the group was reopened and
+    /// before the next one.
+    pub proof: Option<&'a DrawProof>,
+    pub drawn_at: Option<DateTime<Utc>>,
+    /// Whether the draw ran, or it's only committed to so far.
+    pub drawn: bool,
+    pub reveal_at: Option<DateTime<Utc>>,
+    pub verification: Option<&'a Verification>,
+    /// Replaced and reopened draws, oldest first.
+    pub history: &'a [EarlierDraw],
+}
+
+fn rfc3339(at: Option<DateTime<Utc>>) -> String {
+    at.map(|at| at.to_rfc3339()).unwrap_or_default()
+}
+
+fn exclusion_list(exclusions: &[(String, String)]) -> String {
+    exclusions
+        .iter()
+        .map(|(giver, excluded)| format!("{giver} -> {excluded}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl DrawVerification<'_> {
+    fn rows(&self) -> Vec<Vec<String>> {
+        let verification = self.verification;
+        let current = self.proof.map(|proof| {
+            vec![
+                self.group_name.to_string(),
+                proof.commitment.clone(),
+                proof.members.join(", "),
+                exclusion_list(&proof.exclusions),
+                self.drawn.to_string(),
+                rfc3339(self.reveal_at),
+                verification.map(|v| v.seed.clone()).unwrap_or_default(),
+                verification
+                    .map(|v| v.commitment_valid.to_string())
+                    .unwrap_or_default(),
+                verification
+                    .map(|v| v.assignments_valid.to_string())
+                    .unwrap_or_default(),
+                rfc3339(proof.committed_at),
+                rfc3339(self.drawn_at),
+                String::new(),
+                String::new(),
+            ]
+        });
+        let earlier = self.history.iter().map(|draw| {
+            vec![
+                self.group_name.to_string(),
+                draw.commitment.clone(),
+                draw.members.join(", "),
+                exclusion_list(&draw.exclusions),
+                draw.drawn_at.is_some().to_string(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                rfc3339(draw.committed_at),
+                rfc3339(draw.drawn_at),
+                draw.discarded_at.to_rfc3339(),
+                draw.discarded_by.clone().unwrap_or_default(),
+            ]
+        });
+        current.into_iter().chain(earlier).collect()
+    }
+}
+
+impl Render for DrawVerification<'_> {
+    fn text(&self) -> String {
+        let date = |at: DateTime<Utc>| at.format("%Y-%m-%d %H:%M UTC").to_string();
+        let mut out_message = format!("Group name: \"{}\". ", self.group_name);
+        match self.proof {
+            Some(proof) => {
+                out_message += &format!(
+                    "Draw commitment: {}\nMembers: {}\n",
+                    proof.commitment,
+                    proof.members.join(", ")
+                );
+                if !proof.exclusions.is_empty() {
+                    out_message += &format!("Exclusions: {}\n", exclusion_list(&proof.exclusions));
+                }
+                match (self.verification, self.reveal_at) {
+                    _ if !self.drawn => out_message += "The draw hasn't run yet\n",
+                    (Some(v), _) => {
+                        out_message += &format!(
+                            "Seed: {}\nCommitment matches: {}. Assignments match: {}\n",
+                            v.seed, v.commitment_valid, v.assignments_valid
+                        );
+                    }
+                    (None, Some(at)) => {
+                        out_message += &format!("The seed is revealed on {}\n", date(at));
+                    }
+                    (None, None) => out_message += "The seed is revealed after the exchange\n",
+                }
+            }
+            None => out_message += "No draw is committed to right now\n",
+        }
+        if !self.history.is_empty() {
+            out_message += "Earlier draws:\n";
+        }
+        for draw in self.history {
+            out_message += &format!("- {} ({})", draw.commitment, draw.members.join(", "));
+            if let Some(at) = draw.committed_at {
+                out_message += &format!(", committed on {}", date(at));
+            }
+            match draw.drawn_at {
+                Some(at) => out_message += &format!(", drawn on {}", date(at)),
+                None => out_message += ", never drawn",
+            }
+            out_message += &format!(
+                ", discarded on {} by {}\n",
+                date(draw.discarded_at),
+                draw.discarded_by.as_deref().unwrap_or("the scheduler")
+            );
+        }
+        out_message
+    }
+
+    fn json(&self) -> Value {
+        json!({
+            "group_name": self.group_name,
+            "commitment": self.proof.map(|p| &p.commitment),
+            "members": self.proof.map(|p| &p.members),
+            "exclusions": self.proof.map(|p| &p.exclusions),
+            "committed_at": self.proof.and_then(|p| p.committed_at),
+            "drawn": self.drawn,
+            "drawn_at": self.drawn_at,
+            "reveal_at": self.reveal_at,
+            "seed": self.verification.map(|v| &v.seed),
+            "commitment_valid": self.verification.map(|v| v.commitment_valid),
+            "assignments_valid": self.verification.map(|v| v.assignments_valid),
+            "history": self.history
+        })
+    }
+
+    fn html(&self) -> String {
+        html_table(
+            &[
+                "Group name",
+                "Commitment",
+                "Members",
+                "Exclusions",
+                "Drawn",
+                "Revealed on",
+                "Seed",
+                "Commitment matches",
+                "Assignments match",
+                "Committed on",
+                "Drawn on",
+                "Discarded on",
+                "Discarded by",
+            ],
+            &self.rows(),
+        )
+    }
+
+    fn csv(&self) -> String {
+        csv_table(
+            &[
+                "group_name",
+                "commitment",
+                "members",
+                "exclusions",
+                "drawn",
+                "reveal_at",
+                "seed",
+                "commitment_valid",
+                "assignments_valid",
+                "committed_at",
+                "drawn_at",
+                "discarded_at",
+                "discarded_by",
+            ],
+            &self.rows(),
+        )
+    }
+}
+
+pub struct AuditEntries<'a> {
+    pub entries: &'a [Entry],
+}
+
+impl AuditEntries<'_> {
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.entries
+            .iter()
+            .map(|entry| {
+                vec![
+                    entry.timestamp.to_rfc3339(),
+                    entry.actor.clone().unwrap_or_default(),
+                    entry.action.kind().to_string(),
+                    entry.target.clone().unwrap_or_default(),
+                    entry.outcome.clone(),
+                ]
+            })
+            .collect()
+    }
+}
+
+impl Render for AuditEntries<'_> {
+    fn text(&self) -> String {
+        let mut out_message = String::from("Audit log: \n");
+        for row in self.rows() {
+            let [timestamp, actor, action, target, outcome] = &row[..] else {
+                continue;
+            };
+            let actor = if actor.is_empty() { "scheduler" } else { actor };
+            out_message += &format!("{timestamp} {actor} {action}");
+            if !target.is_empty() {
+                out_message += &format!(" {target}");
+            }
+            out_message += &format!(": {outcome}\n");
+        }
+        out_message
+    }
+
+    fn json(&self) -> Value {
+        json!({ "entries": self.entries })
+    }
+
+    fn html(&self) -> String {
+        html_table(
+            &["Time", "Actor", "Action", "Target", "Outcome"],
+            &self.rows(),
+        )
+    }
+
+    fn csv(&self) -> String {
+        csv_table(
+            &["timestamp", "actor", "action", "target", "outcome"],
+            &self.rows(),
+        )
+    }
+}
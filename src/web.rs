@@ -0,0 +1,344 @@
+//! Server-rendered pages for using the service from a browser.
+//!
+//! The pages only show forms; every action posts to the regular API routes,
+//! which answer with HTML because browsers ask for `text/html`. The logged in
+//! name and token, see `auth`, are kept in cookies and passed along as
+//! hidden form fields.
+
+use tide::{http::mime, http::Cookie, Redirect, Request, Response, StatusCode};
+
+use crate::render::{escape_html, html_table, page};
+use crate::{Access, DrawOrigin, State};
+
+pub const NAME_COOKIE: &str = "santa_name";
+pub const TOKEN_COOKIE: &str = "santa_token";
+
+/// The logged in name and token. The token is empty when the member logged
+/// in without one, before they got it.
+fn current_user<S>(req: &Request<S>) -> Option<(String, String)> {
+    let name = req
+        .cookie(NAME_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|name| !name.is_empty())?;
+    let token = req
+        .cookie(TOKEN_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .unwrap_or_default();
+    Some((name, token))
+}
+
+fn html_response(content: &str) -> tide::Result {
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(page(content));
+    res.set_content_type(mime::HTML);
+    Ok(res)
+}
+
+fn form(action: &str, user: &(String, String), fields: &[(&str, &str)], button: &str) -> String {
+    let (name, token) = user;
+    let mut out = format!(
+        "<form method=\"post\" action=\"{}\">\n<input type=\"hidden\" name=\"name\" value=\"{}\">\n",
+        action,
+        escape_html(name)
+    );
+    if !token.is_empty() {
+        out += &format!(
+            "<input type=\"hidden\" name=\"token\" value=\"{}\">\n",
+            escape_html(token)
+        );
+    }
+    for (field, value) in fields {
+        if value.is_empty() {
+            out += &format!(
+                "<label>{} <input type=\"text\" name=\"{}\" required></label>\n",
+                field.replace('_', " "),
+                field
+            );
+        } else {
+            out += &format!(
+                "<input type=\"hidden\" name=\"{}\" value=\"{}\">\n",
+                field,
+                escape_html(value)
+            );
+        }
+    }
+    out += &format!("<button type=\"submit\">{button}</button>\n</form>");
+    out
+}
+
+pub async fn home(req: Request<State>) -> tide::Result {
+    let user = match current_user(&req) {
+        Some(user) => user,
+        None => {
+            return html_response(
+                "<h1>Secret Santa</h1>\n\
+                 <form method=\"post\" action=\"/ui/login\">\n\
+                 <label>Your name <input type=\"text\" name=\"name\" required></label>\n\
+                 <label>Your token, if you are in a group <input type=\"password\" name=\"token\"></label>\n\
+                 <button type=\"submit\">Log in</button>\n</form>",
+            );
+        }
+    };
+
+    let guard = req.state().database.lock().unwrap();
+    let mut links = String::from("<ul>\n");
+    for group in guard.groups.values() {
+        links += &format!(
+            "<li><a href=\"/ui/group?group_name={}\">{}</a>. Persons: {}. {}</li>\n",
+            encode_query(&group.name),
+            escape_html(&group.name),
+            group.people.len(),
+            if group.closed { "Closed" } else { "Open" }
+        );
+    }
+    links += "</ul>";
+
+    html_response(&format!(
+        "<h1>Hello {}!</h1>\n\
+         <form method=\"post\" action=\"/ui/logout\"><button type=\"submit\">Log out</button></form>\n\
+         <h2>Groups</h2>\n{}\n\
+         <h2>Create a group</h2>\n{}\n\
+         <h2>Join a group</h2>\n{}",
+        escape_html(&user.0),
+        links,
+        form("/groups/create", &user, &[("group_name", "")], "Create"),
+        form("/groups/join", &user, &[("group_name", "")], "Join"),
+    ))
+}
+
+pub async fn group(req: Request<State>) -> tide::Result {
+    #[derive(serde::Deserialize, Default)]
+    struct Query {
+        group_name: String,
+    }
+    let Some(user) = current_user(&req) else {
+        return Ok(Redirect::new("/ui").into());
+    };
+    let (name, token) = &user;
+    let Query { group_name } = req.query().unwrap_or_default();
+
+    let guard = req.state().database.lock().unwrap();
+    let Some(group) = guard.groups.values().find(|g| g.name == group_name) else {
+        return html_response("<p>There is no group with that name</p>");
+    };
+
+    let group_field = [("group_name", group_name.as_str())];
+
+    let Some(member) = group.people.iter().find(|p| &p.name == name) else {
+        let join = if group.closed {
+            String::new()
+        } else {
+            form("/groups/join", &user, &group_field, "Join the group")
+        };
+        return html_response(&format!(
+            "<h1>{}</h1>\n<p>Persons: {}. {}</p>\n{}",
+            escape_html(&group.name),
+            group.people.len(),
+            if group.closed { "Closed" } else { "Open" },
+            join
+        ));
+    };
+    if !member.has_token(token) {
+        return html_response(&format!(
+            "<h1>{}</h1>\n<p>Log in with your token to see this group.</p>\n{}",
+            escape_html(&group.name),
+            form(
+                "/groups/token",
+                &user,
+                &group_field,
+                "Send me a new token by email"
+            )
+        ));
+    }
+
+    let rows: Vec<Vec<String>> = group
+        .people
+        .iter()
+        .map(|person| {
+            vec![
+                person.name.clone(),
+                person.display_name.clone().unwrap_or_default(),
+                format!("{:?}", person.access),
+            ]
+        })
+        .collect();
+
+    let actions = if group.closed {
+        form("/to-who-gift", &user, &group_field, "Who do I gift?")
+    } else {
+        let draw = if matches!(member.access, Access::Admin) {
+            form(
+                "/groups/set_santas",
+                &user,
+                &group_field,
+                if group.draw_proof.is_some() {
+                    "Run the draw"
+                } else {
+                    "Publish the draw commitment"
+                },
+            ) + "\n"
+        } else {
+            String::new()
+        };
+        draw + &form("/groups/quit", &user, &group_field, "Quit the group")
+    };
+
+    html_response(&format!(
+        "<h1>{}</h1>\n<p>{}</p>\n{}\n{}",
+        escape_html(&group.name),
+        match (&group.drawn_by, group.closed) {
+            (_, false) => match &group.draw_proof {
+                Some(proof) => format!(
+                    "The draw has not happened yet. Its commitment is {}.",
+                    proof.commitment
+                ),
+                None => "The draw has not happened yet.".to_string(),
+            },
+            (Some(DrawOrigin::System), true) => "The draw was done automatically.".to_string(),
+            (Some(DrawOrigin::Admin { name }), true) => {
+                format!("The draw was done by {}.", escape_html(name))
+            }
+            (None, true) => "The draw is done.".to_string(),
+        },
+        html_table(&["Name", "Display name", "Access"], &rows),
+        actions
+    ))
+}
+
+pub async fn login(mut req: Request<State>) -> tide::Result {
+    #[derive(serde::Deserialize, Default)]
+    struct Login {
+        name: String,
+        #[serde(default)]
+        token: String,
+    }
+    let Login { name, token } = req.body_form().await.unwrap_or_default();
+
+    let mut res: Response = Redirect::see_other("/ui").into();
+    if !name.trim().is_empty() {
+        for (cookie_name, value) in [(NAME_COOKIE, name.trim()), (TOKEN_COOKIE, token.trim())] {
+            let mut cookie = Cookie::new(cookie_name, value.to_string());
+            cookie.set_path("/");
+            cookie.set_http_only(true);
+            res.insert_cookie(cookie);
+        }
+    }
+    Ok(res)
+}
+
+pub async fn logout(_req: Request<State>) -> tide::Result {
+    let mut res: Response = Redirect::see_other("/ui").into();
+    for cookie_name in [NAME_COOKIE, TOKEN_COOKIE] {
+        let mut cookie = Cookie::named(cookie_name);
+        cookie.set_path("/");
+        res.remove_cookie(cookie);
+    }
+    Ok(res)
+}
+
+fn encode_query(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => out += &format!("%{byte:02X}"),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{Method, Request};
+
+    use super::encode_query;
+    use crate::testing::{url, TestApp};
+
+    /// The page at `path` for `name` logged in with `token`.
+    async fn page(app: &TestApp, path: &str, name: &str, token: &str) -> String {
+        let mut req = Request::new(Method::Get, url(path));
+        req.insert_header("Cookie", format!("santa_name={name}; santa_token={token}"));
+        let mut res = app.respond(req).await;
+        assert_eq!(res.status(), 200);
+        res.body_string().await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn logging_in_and_out_sets_and_removes_the_cookies() {
+        let app = TestApp::new();
+        let answer = app.get("/ui").await.body_string().await.unwrap();
+        assert!(answer.contains("action=\"/ui/login\""), "{answer}");
+
+        let mut req = Request::new(Method::Post, url("/ui/login"));
+        req.set_body("name=+alice+&token=secret");
+        req.set_content_type("application/x-www-form-urlencoded".parse().unwrap());
+        let res = app.respond(req).await;
+        assert_eq!(res.status(), 303);
+        let cookies: Vec<_> = res["Set-Cookie"].iter().map(|c| c.as_str()).collect();
+        for cookie in ["santa_name=alice;", "santa_token=secret;"] {
+            let cookie = cookies.iter().find(|c| c.starts_with(cookie));
+            assert!(cookie.unwrap().contains("HttpOnly"), "{cookies:?}");
+        }
+
+        let mut req = Request::new(Method::Post, url("/ui/logout"));
+        req.insert_header("Cookie", "santa_name=alice; santa_token=secret");
+        let res = app.respond(req).await;
+        assert_eq!(res.status(), 303);
+        let cookies: Vec<_> = res["Set-Cookie"].iter().map(|c| c.as_str()).collect();
+        for cookie in ["santa_name=;", "santa_token=;"] {
+            let cookie = cookies.iter().find(|c| c.starts_with(cookie));
+            assert!(cookie.unwrap().contains("Max-Age=0"), "{cookies:?}");
+        }
+    }
+
+    #[async_std::test]
+    async fn the_group_page_needs_the_members_token() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+
+        let home = page(&app, "/ui", "carol", "").await;
+        assert!(home.contains("/ui/group?group_name=party"), "{home}");
+        let answer = page(&app, "/ui/group?group_name=party", "carol", "").await;
+        assert!(answer.contains("Join the group"), "{answer}");
+        assert!(!answer.contains("<table"), "{answer}");
+
+        for token in ["", "guess", &app.token("alice")] {
+            let answer = page(&app, "/ui/group?group_name=party", "bob", token).await;
+            assert!(answer.contains("Send me a new token"), "{answer}");
+            assert!(!answer.contains("<table"), "{answer}");
+        }
+        let answer = page(&app, "/ui/group?group_name=party", "bob", &app.token("bob")).await;
+        assert!(answer.contains("<table"), "{answer}");
+        assert!(answer.contains(&app.token("bob")), "{answer}");
+    }
+
+    #[async_std::test]
+    async fn only_admins_are_offered_the_draw() {
+        let app = TestApp::new();
+        app.group("party", &["alice", "bob"]).await;
+        let path = "/ui/group?group_name=party";
+
+        let answer = page(&app, path, "alice", &app.token("alice")).await;
+        assert!(answer.contains("Publish the draw commitment"), "{answer}");
+        let answer = page(&app, path, "bob", &app.token("bob")).await;
+        assert!(!answer.contains("/groups/set_santas"), "{answer}");
+        assert!(answer.contains("Quit the group"), "{answer}");
+
+        app.draw("party", "alice").await;
+        for name in ["alice", "bob"] {
+            let answer = page(&app, path, name, &app.token(name)).await;
+            assert!(answer.contains("Who do I gift?"), "{answer}");
+            assert!(answer.contains("The draw was done by alice."), "{answer}");
+            assert!(!answer.contains("/groups/set_santas"), "{answer}");
+        }
+    }
+
+    #[test]
+    fn group_names_are_encoded_in_links() {
+        assert_eq!(encode_query("party-2024_a.b~"), "party-2024_a.b~");
+        assert_eq!(encode_query("a b&c=d"), "a%20b%26c%3Dd");
+        assert_eq!(encode_query("ö"), "%C3%B6");
+    }
+}
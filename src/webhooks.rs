@@ -0,0 +1,645 @@
+//! Outbound webhooks. Group admins register URLs that receive every group
+//! event as a JSON `POST`, signed with a per-webhook secret:
+//!
+//! `X-Santa-Signature: sha256=<hex HMAC-SHA256 of the raw body>`
+//!
+//! Deliveries run in the background and are retried with exponential backoff
+//! so a slow or broken receiver never holds up a request.
+//!
+//! Webhooks may only point to public addresses, so group admins can't make
+//! the server call into its own network. The host is checked when the
+//! webhook is added and again on every delivery, in case its DNS record
+//! changed since. Hosts in `webhooks.allowed_hosts` of the config are exempt.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_std::channel::{unbounded, Sender};
+use async_std::net::ToSocketAddrs;
+use async_std::task;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::Sha256;
+use tide::http::url::{Host, Url};
+use tide::Request;
+
+use crate::config::WebhooksConfig;
+use crate::events::GroupEvent;
+use crate::journal::Event;
+use crate::render::reply;
+use crate::{
+    not_saved, persist, read_body, returnable_value, views, Access, DataBase, Group, Person, State,
+};
+
+const ATTEMPTS: u32 = 5;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct Webhook {
+    pub url: String,
+    pub secret: String,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Webhook {
+        let secret = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        Webhook { url, secret }
+    }
+}
+
+struct Delivery {
+    webhook: Webhook,
+    event_name: &'static str,
+    body: String,
+}
+
+#[derive(Clone)]
+pub struct Webhooks {
+    queue: Sender<Delivery>,
+    allowed_hosts: Arc<Vec<String>>,
+}
+
+impl Webhooks {
+    /// Starts the delivery worker on the async-std runtime.
+    pub fn start(config: &WebhooksConfig) -> Webhooks {
+        let allowed_hosts = Arc::new(config.allowed_hosts.clone());
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .redirects(0)
+            .resolver(PublicResolver {
+                allowed_hosts: allowed_hosts.clone(),
+            })
+            .build();
+        let first_retry = Duration::from_millis(config.first_retry_ms);
+
+        let (queue, deliveries) = unbounded::<Delivery>();
+        task::spawn(async move {
+            while let Ok(delivery) = deliveries.recv().await {
+                task::spawn(deliver(agent.clone(), first_retry, delivery));
+            }
+        });
+        Webhooks {
+            queue,
+            allowed_hosts,
+        }
+    }
+
+    /// Checks that a webhook may be registered for `url`: it's http or https
+    /// and its host is allowed or resolves to public addresses only.
+    pub async fn check_url(&self, url: &str) -> Result<(), &'static str> {
+        let url = Url::parse(url).map_err(|_| "Bad webhook url")?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err("Webhook url must be http or https");
+        }
+        let (Some(host), Some(port)) = (url.host(), url.port_or_known_default()) else {
+            return Err("Bad webhook url");
+        };
+        if is_allowed(&self.allowed_hosts, &host.to_string()) {
+            return Ok(());
+        }
+
+        let addresses: Vec<IpAddr> = match host {
+            Host::Ipv4(ip) => vec![ip.into()],
+            Host::Ipv6(ip) => vec![ip.into()],
+            Host::Domain(domain) => (domain, port)
+                .to_socket_addrs()
+                .await
+                .map_err(|_| "Webhook host can't be resolved")?
+                .map(|address| address.ip())
+                .collect(),
+        };
+        if addresses.is_empty() || !addresses.into_iter().all(is_public) {
+            return Err("Webhook url must point to a public address");
+        }
+        Ok(())
+    }
+
+    pub fn send(&self, webhooks: &[Webhook], event: &GroupEvent) {
+        if webhooks.is_empty() {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut payload = serde_json::to_value(event).unwrap();
+        payload["timestamp"] = timestamp.into();
+        let body = payload.to_string();
+
+        for webhook in webhooks {
+            let _ = self.queue.try_send(Delivery {
+                webhook: webhook.clone(),
+                event_name: event.kind(),
+                body: body.clone(),
+            });
+        }
+    }
+}
+
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn is_allowed(allowed_hosts: &[String], host: &str) -> bool {
+    allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+}
+
+/// Whether the address is reachable on the internet, as opposed to the
+/// server itself or its local network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "This network", 0.0.0.0/8.
+                || first == 0
+                // Shared address space of carrier-grade NAT, 100.64.0.0/10.
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    || ip.is_multicast())
+            }
+        },
+    }
+}
+
+/// Resolves delivery hosts like the system does, dropping the addresses a
+/// webhook must not reach.
+struct PublicResolver {
+    allowed_hosts: Arc<Vec<String>>,
+}
+
+impl ureq::Resolver for PublicResolver {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let addresses = std::net::ToSocketAddrs::to_socket_addrs(netloc)?;
+        let host = netloc.rsplit_once(':').map_or(netloc, |(host, _)| host);
+        if is_allowed(&self.allowed_hosts, host) {
+            return Ok(addresses.collect());
+        }
+        let public: Vec<SocketAddr> = addresses
+            .filter(|address| is_public(address.ip()))
+            .collect();
+        if public.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{host} doesn't resolve to a public address"),
+            ));
+        }
+        Ok(public)
+    }
+}
+
+async fn deliver(agent: ureq::Agent, first_retry: Duration, delivery: Delivery) {
+    let signature = sign(&delivery.webhook.secret, &delivery.body);
+    let mut delay = first_retry;
+
+    for attempt in 1..=ATTEMPTS {
+        let agent = agent.clone();
+        let url = delivery.webhook.url.clone();
+        let body = delivery.body.clone();
+        let signature = signature.clone();
+        let event_name = delivery.event_name;
+
+        let result = task::spawn_blocking(move || {
+            agent
+                .post(&url)
+                .set("Content-Type", "application/json")
+                .set("X-Santa-Event", event_name)
+                .set("X-Santa-Signature", &signature)
+                .send_string(&body)
+                .map(|_| ())
+                .map_err(|err| match err {
+                    ureq::Error::Status(code, _) => (Some(code), err.to_string()),
+                    ureq::Error::Transport(_) => (None, err.to_string()),
+                })
+        })
+        .await;
+
+        match result {
+            Ok(()) => return,
+            // The receiver rejected the payload itself, retrying won't help.
+            Err((Some(code), _)) if is_permanent(code) => {
+                tracing::warn!(
+                    url = %delivery.webhook.url,
+                    status = code,
+                    "Webhook delivery rejected"
+                );
+                return;
+            }
+            Err((_, err)) if attempt == ATTEMPTS => {
+                tracing::warn!(
+                    url = %delivery.webhook.url,
+                    attempts = ATTEMPTS,
+                    error = %err,
+                    "Webhook delivery failed"
+                );
+            }
+            Err(_) => {
+                task::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+}
+
+fn is_permanent(code: u16) -> bool {
+    (400..500).contains(&code) && code != 408 && code != 429
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+#[serde(default)]
+struct Data {
+    name: String,
+    group_name: String,
+    url: String,
+}
+
+/// Checks that `data.name` administers the group and hands it to `action`.
+fn with_admin_group<T>(
+    database: &mut DataBase,
+    data: &Data,
+    action: impl FnOnce(&mut Group) -> T,
+) -> Result<T, (&'static str, u16)> {
+    let group = database
+        .groups
+        .values_mut()
+        .find(|g| g.name == data.group_name)
+        .ok_or(("Group with that name does not exist", 400))?;
+    match group.people.iter().find(|p| p.name == data.name) {
+        Some(Person {
+            access: Access::Admin,
+            ..
+        }) => Ok(action(group)),
+        _ => Err(("Only the administrator can manage webhooks", 403)),
+    }
+}
+
+pub async fn add(mut req: Request<State>) -> tide::Result {
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+    if let Err(text) = req.state().webhooks.check_url(&data.url).await {
+        return returnable_value(&req, text, 400);
+    }
+
+    let state = req.state();
+    let mut guard = state.database.lock().unwrap();
+
+    let result = with_admin_group(&mut guard, &data, |group| {
+        if group.webhooks.iter().any(|w| w.url == data.url) {
+            return None;
+        }
+        let webhook = Webhook::new(data.url.clone());
+        group.webhooks.push(webhook.clone());
+        Some(webhook)
+    });
+
+    if let Ok(Some(_)) = result {
+        if persist(state, &mut guard, &data.group_name, Event::WebhooksChanged).is_err() {
+            return not_saved(&req);
+        }
+    }
+
+    match result {
+        Ok(Some(webhook)) => reply(
+            &req,
+            200,
+            &views::WebhookAdded {
+                url: &webhook.url,
+                secret: &webhook.secret,
+            },
+        ),
+        Ok(None) => returnable_value(&req, "This webhook is already registered", 400),
+        Err((text, code)) => returnable_value(&req, text, code),
+    }
+}
+
+pub async fn remove(mut req: Request<State>) -> tide::Result {
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() || data.url.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+
+    let state = req.state();
+    let mut guard = state.database.lock().unwrap();
+
+    let result = with_admin_group(&mut guard, &data, |group| {
+        let count = group.webhooks.len();
+        group.webhooks.retain(|w| w.url != data.url);
+        count != group.webhooks.len()
+    });
+
+    if let Ok(true) = result {
+        if persist(state, &mut guard, &data.group_name, Event::WebhooksChanged).is_err() {
+            return not_saved(&req);
+        }
+    }
+
+    match result {
+        Ok(true) => returnable_value(&req, "Webhook removed", 200),
+        Ok(false) => returnable_value(&req, "There is no such webhook", 400),
+        Err((text, code)) => returnable_value(&req, text, code),
+    }
+}
+
+pub async fn list(mut req: Request<State>) -> tide::Result {
+    let data: Data = read_body(&mut req).await;
+
+    if data.name.is_empty() || data.group_name.is_empty() {
+        return returnable_value(&req, "Bad data", 400);
+    }
+
+    let mut guard = req.state().database.lock().unwrap();
+    let result = with_admin_group(&mut guard, &data, |group| {
+        group
+            .webhooks
+            .iter()
+            .map(|w| w.url.clone())
+            .collect::<Vec<_>>()
+    });
+
+    match result {
+        Ok(urls) => reply(&req, 200, &views::WebhookList { urls: &urls }),
+        Err((text, code)) => returnable_value(&req, text, code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use serde_json::json;
+
+    use super::{is_public, sign, Webhook, Webhooks, ATTEMPTS};
+    use crate::config::WebhooksConfig;
+    use crate::events::GroupEvent;
+    use crate::testing::TestApp;
+
+    struct Received {
+        at: Instant,
+        headers: HashMap<String, String>,
+        body: String,
+    }
+
+    /// Starts a receiver on an ephemeral port that answers with `statuses`
+    /// in turn, repeating the last one. Returns its URL.
+    fn receiver(statuses: Vec<u16>) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, received) = mpsc::channel();
+        thread::spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = HashMap::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+                    }
+                }
+                let length = headers["content-length"].parse().unwrap();
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                let status = statuses[index.min(statuses.len() - 1)];
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+                let received = Received {
+                    at: Instant::now(),
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                };
+                if sender.send(received).is_err() {
+                    return;
+                }
+            }
+        });
+        (url, received)
+    }
+
+    fn app() -> TestApp {
+        TestApp::with_config(|config| {
+            config.webhooks.allowed_hosts = vec!["127.0.0.1".to_string()];
+            config.webhooks.first_retry_ms = 20;
+        })
+    }
+
+    /// Registers `url` for a new group and returns the webhook secret.
+    async fn register(app: &TestApp, url: &str) -> String {
+        app.group("party", &["alice"]).await;
+        let (code, message) = app
+            .post(
+                "/groups/webhooks/add",
+                json!({ "name": "alice", "group_name": "party", "url": url }),
+            )
+            .await;
+        assert_eq!(code, 200, "{message}");
+        message["secret"].as_str().unwrap().to_string()
+    }
+
+    async fn join(app: &TestApp, name: &str) {
+        let body = json!({ "name": name, "group_name": "party" });
+        assert_eq!(app.post("/groups/join", body).await.0, 200);
+    }
+
+    fn next(received: &mpsc::Receiver<Received>) -> Received {
+        received.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    fn assert_no_more(received: &mpsc::Receiver<Received>) {
+        assert!(received.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[async_std::test]
+    async fn deliveries_are_signed() {
+        let app = app();
+        let (url, received) = receiver(vec![200]);
+        let secret = register(&app, &url).await;
+
+        join(&app, "bob").await;
+        let delivery = next(&received);
+        assert_eq!(delivery.headers["x-santa-event"], "member_joined");
+        assert_eq!(
+            delivery.headers["x-santa-signature"],
+            sign(&secret, &delivery.body)
+        );
+        let payload: serde_json::Value = serde_json::from_str(&delivery.body).unwrap();
+        assert_eq!(payload["group_name"], "party");
+        assert_eq!(payload["name"], "bob");
+        assert_no_more(&received);
+    }
+
+    #[async_std::test]
+    async fn failed_deliveries_are_retried_with_backoff() {
+        let app = app();
+        let (url, received) = receiver(vec![500, 503, 429, 200]);
+        register(&app, &url).await;
+
+        join(&app, "bob").await;
+        let attempts: Vec<Received> = (0..4).map(|_| next(&received)).collect();
+        assert_no_more(&received);
+
+        let gaps: Vec<Duration> = attempts.windows(2).map(|w| w[1].at - w[0].at).collect();
+        assert!(gaps[0] >= Duration::from_millis(20));
+        assert!(gaps[1] >= Duration::from_millis(40));
+        assert!(gaps[2] >= Duration::from_millis(80));
+        assert!(attempts.iter().all(|a| a.body == attempts[0].body));
+    }
+
+    #[async_std::test]
+    async fn delivery_gives_up_after_the_last_attempt() {
+        let app = app();
+        let (url, received) = receiver(vec![500]);
+        register(&app, &url).await;
+
+        join(&app, "bob").await;
+        for _ in 0..ATTEMPTS {
+            next(&received);
+        }
+        assert_no_more(&received);
+    }
+
+    #[async_std::test]
+    async fn rejected_deliveries_are_not_retried() {
+        for status in [400, 404, 410] {
+            let app = app();
+            let (url, received) = receiver(vec![status, 200]);
+            register(&app, &url).await;
+
+            join(&app, "bob").await;
+            next(&received);
+            assert_no_more(&received);
+        }
+    }
+
+    #[async_std::test]
+    async fn webhooks_must_point_to_public_addresses() {
+        let app = TestApp::new();
+        app.group("party", &["alice"]).await;
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.1/hook",
+            "http://[::1]/hook",
+            "http://[::ffff:192.168.0.1]/hook",
+            "ftp://example.com/hook",
+            "not a url",
+        ] {
+            let (code, message) = app
+                .post(
+                    "/groups/webhooks/add",
+                    json!({ "name": "alice", "group_name": "party", "url": url }),
+                )
+                .await;
+            assert_eq!(code, 400, "{url}: {message}");
+        }
+    }
+
+    #[async_std::test]
+    async fn deliveries_to_private_addresses_are_dropped() {
+        // Registered before the host was found to be private, say by a DNS
+        // record that changed.
+        let (url, received) = receiver(vec![200]);
+        let webhooks = Webhooks::start(&WebhooksConfig {
+            allowed_hosts: Vec::new(),
+            first_retry_ms: 1,
+        });
+        webhooks.send(
+            &[Webhook::new(url)],
+            &GroupEvent::MemberJoined {
+                group_name: "party".to_string(),
+                name: "bob".to_string(),
+            },
+        );
+        assert_no_more(&received);
+    }
+
+    #[async_std::test]
+    async fn unsaved_webhook_changes_are_rolled_back() {
+        let app = app();
+        register(&app, "http://127.0.0.1:9/hook").await;
+        app.state.journal.fail_writes();
+
+        for (path, url) in [
+            ("/groups/webhooks/add", "http://127.0.0.1:9/other"),
+            ("/groups/webhooks/remove", "http://127.0.0.1:9/hook"),
+        ] {
+            let body = json!({ "name": "alice", "group_name": "party", "url": url });
+            let (code, message) = app.post(path, body).await;
+            assert_eq!(code, 500, "{path}: {message}");
+        }
+
+        let body = json!({ "name": "alice", "group_name": "party" });
+        let (code, message) = app.post("/groups/webhooks", body).await;
+        assert_eq!(code, 200, "{message}");
+        assert_eq!(message["webhooks"], json!(["http://127.0.0.1:9/hook"]));
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "0.0.0.0",
+            "0.1.2.3",
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "255.255.255.255",
+            "224.0.0.1",
+            "::",
+            "::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}